@@ -1,6 +1,9 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kvs::engines::kv::KvOptions;
 use kvs::{KvStore, KvsEngine, SledStore};
 use rand::prelude::*;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -11,7 +14,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -25,7 +28,44 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    // records written out by the log's flusher or when the buffer fills
+    group.bench_function("kvs_group_commit", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let options = KvOptions {
+                    flush_every: Some(Duration::from_millis(500)),
+                    ..KvOptions::default()
+                };
+                let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                (store, temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    // writes flushed by sled's background flusher instead of one by one
+    group.bench_function("sled_batched_flush", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let flush_every = Some(Duration::from_millis(500));
+                let db = SledStore::open_with_options(temp_dir.path(), flush_every).unwrap();
+                (db, temp_dir)
+            },
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -38,10 +78,10 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -55,10 +95,10 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledStore::open(temp_dir.path()).unwrap();
+            let db = SledStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -72,5 +112,85 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// 8 reader threads and 1 writer thread sharing one store
+fn concurrent_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_bench");
+    group.bench_function("kvs", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key_i in 0..1000 {
+            store
+                .set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        b.iter(|| {
+            thread::scope(|s| {
+                for reader in 0..8 {
+                    let store = store.clone();
+                    s.spawn(move || {
+                        for i in 0..100 {
+                            store
+                                .get(format!("key{}", (reader * 100 + i) % 1000))
+                                .unwrap();
+                        }
+                    });
+                }
+                let store = store.clone();
+                s.spawn(move || {
+                    for i in 0..100 {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                });
+            });
+        })
+    });
+    group.finish();
+}
+
+// 8 threads reading at once, nothing writing: gets only contend if the engine serializes reads
+fn parallel_get_bench(c: &mut Criterion) {
+    fn read_in_parallel(store: &impl KvsEngine) {
+        thread::scope(|s| {
+            for reader in 0..8 {
+                s.spawn(move || {
+                    for i in 0..100 {
+                        store
+                            .get(format!("key{}", (reader * 100 + i) % 1000))
+                            .unwrap();
+                    }
+                });
+            }
+        });
+    }
+
+    let mut group = c.benchmark_group("parallel_get_bench");
+    group.bench_function("kvs", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for key_i in 0..1000 {
+            store
+                .set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        b.iter(|| read_in_parallel(&store))
+    });
+    group.bench_function("sled", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let db = SledStore::open(temp_dir.path()).unwrap();
+        for key_i in 0..1000 {
+            db.set(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        b.iter(|| read_in_parallel(&db))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    concurrent_bench,
+    parallel_get_bench
+);
 criterion_main!(benches);
@@ -1,11 +1,19 @@
-use clap::{arg, Arg, Command};
-use std::{env::current_dir, process::exit};
+use clap::{arg, Arg, ArgMatches, Command};
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::process::exit;
 
-use kvs::{Command as kCommand, Record, Result};
-use std::io::{Read, Write};
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use kvs::proto::{parse_addr, KeyResult};
+use kvs::{KvsClient, Result, WriteBatch};
 
-fn main() -> Result<()> {
+fn main() {
+    if let Err(e) = try_main() {
+        eprintln!("{e}");
+        exit(1);
+    }
+}
+
+fn try_main() -> Result<()> {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -33,6 +41,31 @@ fn main() -> Result<()> {
                             If --addr is not specified then listen on 127.0.0.1:4000"),
                 ]),
         )
+        .subcommand(
+            Command::new("mget")
+                .about("Get the values of several keys, printing one line per key")
+                .arg(
+                    Arg::new("KEY")
+                        .help("The keys")
+                        .required(true)
+                        .num_args(1..),
+                )
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
+        .subcommand(
+            Command::new("exists")
+                .about("Print whether a key has a value, true or false")
+                .arg(Arg::new("KEY").help("A key").required(true))
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
         .subcommand(
             Command::new("rm")
                 .about("Remove the key-value pair")
@@ -47,6 +80,56 @@ fn main() -> Result<()> {
                             If --addr is not specified then listen on 127.0.0.1:4000"),
                 ]),
         )
+        .subcommand(
+            Command::new("clear")
+                .about("Remove every key-value pair")
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
+        .subcommand(
+            Command::new("batch")
+                .about(
+                    "Apply the operations read from stdin in one request, one per line: \
+                     \"set KEY VALUE\" or \"rm KEY\"",
+                )
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("Write every key-value pair to FILE, one JSON object per line")
+                .arg(Arg::new("FILE").help("The file to write").required(true))
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
+        .subcommand(
+            Command::new("load")
+                .about("Set every key-value pair of a FILE written by dump")
+                .arg(Arg::new("FILE").help("The file to read").required(true))
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Print the number of keys, bytes on disk and reclaimable stale bytes")
+                .args([
+                    arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
+                            either v4 or v6, and a port number, with the format IP:PORT. 
+                            If --addr is not specified then listen on 127.0.0.1:4000"),
+                ]),
+        )
         .args(
             [
                 arg!(-a --addr <IPADDR> "Accepts an IP address to be connected to, 
@@ -60,88 +143,95 @@ fn main() -> Result<()> {
         .get_matches();
 
     let default_ip = "127.0.0.1:4000".to_string();
-    let mut ip = matches.get_one::<String>("addr").unwrap_or(&default_ip);
+    let ip = matches.get_one::<String>("addr").unwrap_or(&default_ip);
 
     match matches.subcommand() {
-        Some(("set", _matches)) => {
-            ip = _matches.get_one::<String>("addr").unwrap_or(&ip);
-            let mut socket = TcpStream::connect(ip)?;
-            let record = Record {
-                cmd: kCommand::Set,
-                key: _matches
-                    .get_one::<String>("KEY")
-                    .expect("required")
-                    .to_string(),
-                value: _matches
-                    .get_one::<String>("VALUE")
-                    .expect("required")
-                    .to_string(),
-            };
-            let buffer = serde_json::to_string(&record)?;
-            socket.write(&(buffer.len() as u32 + 4).to_be_bytes())?;
-            socket.write(buffer.as_bytes())?;
-            socket.flush()?;
-            let mut value = String::new();
-            socket.try_clone()?.read_to_string(&mut value)?;
-            if value.starts_with("ERROR") {
-                //println!("{value}");
-                exit(1);
-            } else {
-                exit(0);
-            }
+        Some(("set", sub)) => {
+            let value = sub.get_one::<String>("VALUE").expect("required");
+            // the previous value isn't printed
+            connect(sub, ip)?.set(key(sub), value.to_owned())?;
         }
-        Some(("get", _matches)) => {
-            ip = _matches.get_one::<String>("addr").unwrap_or(&ip);
-            let mut socket = TcpStream::connect(ip)?;
-            let record = Record {
-                cmd: kCommand::Get,
-                key: _matches
-                    .get_one::<String>("KEY")
-                    .expect("required")
-                    .to_string(),
-                value: "".to_string(),
-            };
-            let buffer = serde_json::to_string(&record)?;
-            socket.write(&(buffer.len() as u32 + 4).to_be_bytes())?;
-            socket.write(buffer.as_bytes())?;
-            socket.flush()?;
-            let mut value = String::new();
-            socket.try_clone()?.read_to_string(&mut value)?;
-            if value.starts_with("ERROR") {
-                println!("Key not found");
-                exit(0);
-            } else {
-                println!("{value}");
-                exit(0);
+        Some(("get", sub)) => match connect(sub, ip)?.get(key(sub))? {
+            Some(value) => println!("{value}"),
+            None => println!("Key not found"),
+        },
+        Some(("mget", sub)) => {
+            let keys = sub
+                .get_many::<String>("KEY")
+                .expect("required")
+                .cloned()
+                .collect();
+            for result in connect(sub, ip)?.get_many(keys)? {
+                match result {
+                    KeyResult::Found(value) => println!("{value}"),
+                    KeyResult::NotFound => println!("Key not found"),
+                    KeyResult::Error(msg) => println!("Error: {msg}"),
+                }
             }
         }
-        Some(("rm", _matches)) => {
-            ip = _matches.get_one::<String>("addr").unwrap_or(&ip);
-            let mut socket = TcpStream::connect(ip)?;
-            let record = Record {
-                cmd: kCommand::Remove,
-                key: _matches
-                    .get_one::<String>("KEY")
-                    .expect("required")
-                    .to_string(),
-                value: "".to_string(),
-            };
-            let buffer = serde_json::to_string(&record)?;
-            socket.write(&(buffer.len() as u32 + 4).to_be_bytes())?;
-            socket.write(buffer.as_bytes())?;
-            socket.flush()?;
-            let mut value = String::new();
-            socket.try_clone()?.read_to_string(&mut value)?;
-            if value.starts_with("ERROR") {
-                eprintln!("Key not found");
-                //println!("{value}");
-                exit(1);
-            } else {
-                exit(0);
-            }
+        Some(("exists", sub)) => println!("{}", connect(sub, ip)?.exists(key(sub))?),
+        Some(("rm", sub)) => {
+            connect(sub, ip)?.remove(key(sub))?;
+        }
+        Some(("clear", sub)) => connect(sub, ip)?.clear()?,
+        Some(("batch", sub)) => {
+            // read all of stdin before tying up a server worker
+            let batch = read_batch()?;
+            connect(sub, ip)?.write_batch(batch)?;
+        }
+        Some(("dump", sub)) => {
+            let mut client = connect(sub, ip)?;
+            client.export(File::create(file(sub))?)?;
+        }
+        Some(("load", sub)) => {
+            let mut client = connect(sub, ip)?;
+            client.import(File::open(file(sub))?)?;
+        }
+        Some(("stats", sub)) => {
+            let stats = connect(sub, ip)?.stats()?;
+            println!("keys: {}", stats.keys);
+            println!("disk_bytes: {}", stats.disk_bytes);
+            println!("stale_bytes: {}", stats.stale_bytes);
         }
         _ => unreachable!(),
     }
-
     Ok(())
 }
+
+/// Connect to the subcommand's `--addr`, or to `default` without one.
+fn connect(matches: &ArgMatches, default: &str) -> Result<KvsClient> {
+    let addr = matches
+        .get_one::<String>("addr")
+        .map_or(default, String::as_str);
+    KvsClient::connect(parse_addr(addr)?)
+}
+
+fn file(matches: &ArgMatches) -> &String {
+    matches.get_one::<String>("FILE").expect("required")
+}
+
+fn key(matches: &ArgMatches) -> String {
+    matches
+        .get_one::<String>("KEY")
+        .expect("required")
+        .to_owned()
+}
+
+/// Read batch operations from stdin, exiting on a line that isn't one.
+fn read_batch() -> Result<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    for (line_num, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        let mut words = line.splitn(3, ' ');
+        match (words.next(), words.next(), words.next()) {
+            (Some("set"), Some(key), Some(value)) => batch.set(key.to_owned(), value.to_owned()),
+            (Some("rm"), Some(key), None) => batch.remove(key.to_owned()),
+            (Some(""), None, None) => {}
+            _ => {
+                eprintln!("Invalid operation on line {}: {line}", line_num + 1);
+                exit(1);
+            }
+        }
+    }
+    Ok(batch)
+}
@@ -1,131 +1,57 @@
-use clap::{arg, Command};
+use clap::{arg, value_parser, ArgMatches, Command, ValueEnum};
 use kvs::engines::sled::SledStore;
-use kvs::thread_pool::{SharedQueueThreadPool, RayonThreadPool};
-use kvs::{Command as kCommand, KvStore, KvsEngine, Record, Result};
-use kvs::{NaiveThreadPool, ThreadPool};
-use log::{debug, error, info, warn};
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
-use std::sync::Arc;
+use kvs::proto::parse_addr;
+use kvs::server::{KvServer, ServerConfig};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool};
+use kvs::{KvStore, KvsEngine, KvsError, Limits, Result, ThreadPool};
+use log::{error, info};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use std::{env::current_dir, process::exit};
 use stderrlog::{self, LogLevelNum, Timestamp};
 
-#[derive(Serialize, Deserialize)]
-pub struct KvServer {
-    engine: String,
-}
-
-impl KvServer {
-    pub fn load(path: impl Into<PathBuf>) -> Result<KvServer> {
-        let value = std::fs::read_to_string(path.into())?;
-        let server: KvServer = serde_json::from_str(&value)?;
-        Ok(server)
-    }
-
-    pub fn new(path: impl Into<PathBuf>, engine: String) -> Result<KvServer> {
-        let server = KvServer { engine };
-        let value = serde_json::to_string(&server)?;
-        let mut f = File::create(path.into())?;
-        f.write(value.as_bytes())?;
-        f.flush()?;
-        Ok(server)
-    }
-
-    fn serve(socket: TcpStream, store: impl KvsEngine) {
-        info!("New client: {}", socket.peer_addr().unwrap());
-
-        let mut reader = BufReader::new(socket.try_clone().unwrap());
-        let mut writer = BufWriter::new(socket);
-
-        // body
-        let mut buf: [u8; 4] = [0; 4];
-        let n = reader.read(&mut buf).unwrap();
-        if n != buf.len() {
-            error!("Corrupted request, not reading enough bytes");
-        }
-        // big end in network programming
-        let length = u32::from_be_bytes(buf);
-        debug!("The total packet length is: {length}");
-        let mut chunk = reader.take((length - 4).into());
-        debug!("{:?}", chunk);
-        let mut value = String::new();
-        let n = chunk.read_to_string(&mut value).unwrap();
-        debug!("{value}");
-        if n < (length - 4) as usize {
-            error!("Corrupted request, not reading enough bytes");
-        }
-        let record: Record = serde_json::from_str(&value).unwrap();
-        match record.cmd {
-            kCommand::Set => {
-                match store.set(record.key, record.value) {
-                    Ok(_) => writer.write("Successful set operation".as_bytes()).unwrap(),
-                    Err(e) => {
-                        writer.write("ERROR: ".as_bytes()).unwrap()
-                            + writer.write(e.to_string().as_bytes()).unwrap()
-                    }
-                };
-                // socket.shutdown(Shutdown::Both)?;
-            }
-            kCommand::Get => {
-                match store.get(record.key.clone()).unwrap() {
-                    None => {
-                        writer
-                            .write("ERROR: NO such key in storage".as_bytes())
-                            .unwrap();
-                        warn!("NO such key in storage: {}", record.key);
-                    }
-                    Some(value) => {
-                        let len = value.len() as u32;
-                        // writer.write(&len.to_be_bytes())?;
-                        writer.write(value.as_bytes()).unwrap();
-                    }
-                };
-                // socket.shutdown(Shutdown::Both)?;
-            }
-            kCommand::Remove => {
-                match store.remove(record.key) {
-                    Ok(_) => writer
-                        .write("Successful remove operation".as_bytes())
-                        .unwrap(),
-                    Err(e) => {
-                        writer.write("ERROR: ".as_bytes()).unwrap()
-                            + writer.write(e.to_string().as_bytes()).unwrap()
-                    }
-                };
-                // socket.shutdown(Shutdown::Both)?;
-            }
-        }
-        writer.flush().unwrap();
-    }
+/// How often sled is flushed in the background when group commit syncs the writes.
+const SLED_FLUSH_EVERY: Duration = Duration::from_secs(1);
 
-    pub fn start(&self, ip: &String, store: impl KvsEngine, pool: impl ThreadPool) -> Result<()> {
-        let engine = &self.engine;
-        info!(env!("CARGO_PKG_VERSION"));
-        info!("ENGINE: {engine}, IP: {ip}");
+/// Left in the data directory while `--import-from` runs, so a start after an interrupted
+/// import knows the data there is partial.
+const IMPORT_MARKER: &str = "import.partial";
 
-        let listener = TcpListener::bind(ip)?;
-        info!("Listen at {ip}");
-
-        for socket in listener.incoming() {
-            let n_store = store.clone();
-            pool.spawn(move || Self::serve(socket.unwrap(), n_store))
-        }
+/// Thread pool the server serves connections on.
+#[derive(Clone, Copy, ValueEnum)]
+enum PoolKind {
+    Naive,
+    #[value(name = "shared_queue")]
+    SharedQueue,
+    Rayon,
+}
 
-        Ok(())
+impl PoolKind {
+    /// The name `--thread-pool` and config.json use.
+    fn name(self) -> String {
+        let value = self.to_possible_value().expect("no variant is skipped");
+        value.get_name().to_owned()
     }
 }
 
-fn main() -> Result<()> {
+fn main() {
     stderrlog::new()
         .module(module_path!())
+        .module("kvs")
         .timestamp(Timestamp::Second)
         .verbosity(LogLevelNum::Debug)
         .init()
         .unwrap();
 
+    if let Err(e) = try_main() {
+        error!("{e}");
+        exit(1);
+    }
+}
+
+fn try_main() -> Result<()> {
     let matches = Command::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -137,58 +63,336 @@ fn main() -> Result<()> {
                 either v4 or v6, and a port number, with the format IP:PORT. 
                  If --addr is not specified then listen on 127.0.0.1:4000"),
                 arg!(-e --engine <ENGINE_NAME> "If --engine is specified, then ENGINE-NAME must be either \"kvs\" 
-                , in which case the built-in engine is used, or \"sled\", in which case 
-                 sled is used. If this is the first run (there is no data previously persisted) 
+                , in which case the built-in engine is used, \"sled\", in which case 
+                 sled is used, or \"auto\", in which case the engine is inferred from the 
+                 data already persisted in the working directory. If this is the first run (there is no data previously persisted) 
                   then the default value is \"kvs\"; 
                   if there is previously persisted data 
                   then the default is the engine already in use. 
                   If data was previously persisted with a different engine than selected, 
-                  print an error and exit with a non-zero exit code."),
-                arg!(-t --thread-pool <THREADPOOL_NAME> "This option is for benchmark. 
-                Specify the threadpool used. It must be one of naive, shared_queue or rayon"),
-                arg!(-n --worker-num <WORKER_NUM> "This option is for benchmark. 
+                  print an error and exit with a non-zero exit code.")
+                .value_parser(["kvs", "sled", "auto"]),
+                arg!(-t --"thread-pool" <THREADPOOL_NAME> "This option is for benchmark. 
+                Specify the threadpool used. It must be one of naive, shared_queue or rayon")
+                .value_parser(value_parser!(PoolKind)),
+                arg!(-n --"worker-num" <WORKER_NUM> "This option is for benchmark. 
                 Specify the worker num of the thread pool. Default 8")
+                .value_parser(value_parser!(u32)),
+                arg!(--"cache-capacity" <ENTRIES> "Number of get results to cache in front of 
+                the engine. 0 disables the cache. Default 0, or the value saved in config.json")
+                .value_parser(value_parser!(usize)),
+                arg!(--"cache-ttl-ms" <MILLIS> "How long a cached get result is served. 
+                Default 100, or the value saved in config.json")
+                .value_parser(value_parser!(u64)),
+                arg!(--"max-connections" <CONNECTIONS> "Number of connections served at once. 
+                Clients past it are told the server is busy and disconnected. Defaults to the worker 
+                count, or the value saved in config.json")
+                .value_parser(value_parser!(usize)),
+                arg!(--"commit-window-ms" <MILLIS> "How long a write waits for concurrent writes 
+                to share one engine sync before it is answered. 0 leaves syncing to the engine. 
+                Default 0, or the value saved in config.json")
+                .value_parser(value_parser!(u64)),
+                arg!(--"commit-batch-size" <WRITES> "Writes that end the commit window early once 
+                they are all waiting. Default 64, or the value saved in config.json")
+                .value_parser(value_parser!(usize)),
+                arg!(--"idle-timeout-ms" <MILLIS> "How long a connection may wait for its next 
+                request before it is closed. 0 keeps idle connections open. Default 30000, or 
+                the value saved in config.json")
+                .value_parser(value_parser!(u64)),
+                arg!(--"data-dir" <DIR> "Directory holding the engine's data and config.json, 
+                created if missing. Defaults to the working directory")
+                .value_parser(value_parser!(PathBuf)),
+                arg!(--"import-from" <DIR> "Directory of another store to copy every key-value 
+                pair from before serving, if the data directory holds no data yet. Later starts 
+                ignore it, unless the import was interrupted: it is then required to finish it")
+                .value_parser(value_parser!(PathBuf)),
+                arg!(--"import-engine" <ENGINE> "The engine of the --import-from data. Defaults 
+                to the engine inferred from the data")
+                .requires("import-from")
+                .value_parser(["kvs", "sled"]),
             ]
-        ).get_matches();
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about("Copy every key-value pair into a fresh data directory, converting it to \
+                    another engine, then exit")
+                .args([
+                    arg!(--from <ENGINE> "The engine of the data to copy")
+                        .required(true)
+                        .value_parser(["kvs", "sled"]),
+                    arg!(--to <ENGINE> "The engine to copy the data into")
+                        .required(true)
+                        .value_parser(["kvs", "sled"]),
+                    arg!(--"data-dir" <DIR> "Directory holding the data to copy. Defaults to the \
+                        working directory")
+                        .value_parser(value_parser!(PathBuf)),
+                    arg!(--dest <DIR> "Directory to write the copy and its config.json to, \
+                        created if missing")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                    arg!(--force "Copy into DIR even if it already holds data of the target \
+                        engine, overwriting the keys present in both"),
+                ]),
+        )
+        .get_matches();
+
+    if let Some(("migrate", sub)) = matches.subcommand() {
+        return migrate(sub);
+    }
 
     let default_ip = "127.0.0.1:4000".to_string();
-    let default_engine = "kvs".to_string();
-    let default_thread_pool = "shared_queue".to_string();
-    let default_worker_num = 8;
+    // without --engine, use whatever engine the data directory already holds
+    let default_engine = "auto".to_string();
 
-    let ip = matches.get_one::<String>("addr").unwrap_or(&default_ip);
+    let ip = parse_addr(matches.get_one::<String>("addr").unwrap_or(&default_ip))?;
     let engine = matches
         .get_one::<String>("engine")
         .unwrap_or(&default_engine);
-    let thread_pool = matches.get_one::<String>("thread-pool").unwrap_or(&default_engine);
-    let worker_num = matches.get_one::<u32>("worker-num").unwrap_or(&default_worker_num);
 
-    if engine != "kvs" && engine != "sled" {
-        error!("Invalid engine. Must be 'kvs' or 'sled'");
-        exit(1);
-    }
+    let data_dir = match matches.get_one::<PathBuf>("data-dir") {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
+    std::fs::create_dir_all(&data_dir)?;
+    let engine = if engine == "auto" {
+        detect_engine(&data_dir).unwrap_or("kvs")
+    } else {
+        engine
+    };
 
-    let server: KvServer;
-    let path = current_dir()?.join("config.json");
-    if path.exists() {
-        server = KvServer::load(path)?;
-        if server.engine != engine.to_string() {
-            eprintln!("Wrong engine");
-            exit(1);
+    // the data files themselves win over a missing or hand-edited config.json
+    let persisted = detect_engine(&data_dir);
+    if let Some(found) = persisted.filter(|found| *found != engine) {
+        return Err(KvsError::EngineMismatch {
+            expected: engine.to_string(),
+            found: found.to_string(),
+        });
+    }
+    // a partial import counts as no data, to be imported again over what got copied
+    let interrupted = data_dir.join(IMPORT_MARKER).exists();
+    let import = match persisted {
+        Some(_) if !interrupted => None,
+        _ => import_source(&matches)?,
+    };
+    if interrupted && import.is_none() {
+        return Err(KvsError::ImportInterrupted(data_dir));
+    }
+    let path = data_dir.join("config.json");
+    let mut config = if path.exists() {
+        let config = ServerConfig::load(&path)?;
+        if config.engine != engine {
+            return Err(KvsError::EngineMismatch {
+                expected: engine.to_string(),
+                found: config.engine,
+            });
         }
+        config
     } else {
-        server = KvServer::new(path, engine.to_string())?;
+        ServerConfig::new(engine.to_string())
+    };
+    config.addr = ip.to_string();
+    config.data_dir = data_dir.clone();
+    if let Some(thread_pool) = matches.get_one::<PoolKind>("thread-pool") {
+        config.thread_pool = thread_pool.name();
+    }
+    if let Some(worker_num) = matches.get_one::<u32>("worker-num") {
+        config.worker_num = *worker_num;
+    }
+    if let Some(capacity) = matches.get_one::<usize>("cache-capacity") {
+        config.cache_capacity = *capacity;
+    }
+    if let Some(ttl) = matches.get_one::<u64>("cache-ttl-ms") {
+        config.cache_ttl_ms = *ttl;
+    }
+    if let Some(max) = matches.get_one::<usize>("max-connections") {
+        config.max_connections = Some(*max);
+    }
+    if let Some(window) = matches.get_one::<u64>("commit-window-ms") {
+        config.commit_window_ms = *window;
     }
+    if let Some(size) = matches.get_one::<usize>("commit-batch-size") {
+        config.commit_batch_size = *size;
+    }
+    if let Some(timeout) = matches.get_one::<u64>("idle-timeout-ms") {
+        config.idle_timeout_ms = *timeout;
+    }
+    // the flag is checked by clap, this catches a hand-edited config.json
+    let thread_pool = PoolKind::from_str(&config.thread_pool, false).map_err(|_| {
+        KvsError::ThreadPool(format!(
+            "Unknown thread pool {:?} in config.json, must be naive, shared_queue or rayon",
+            config.thread_pool
+        ))
+    })?;
+    config.save(&path)?;
+    if import.is_some() {
+        // before the store opens, as it may leave data files behind on its own
+        std::fs::write(data_dir.join(IMPORT_MARKER), [])?;
+    }
+    let limits = config.limits();
+    let group_commit = config.commit_window_ms > 0;
+    let (dir, n) = (&data_dir, config.worker_num);
+    let server = KvServer::new(config);
 
-    let pool = SharedQueueThreadPool::new(8)?;
+    match thread_pool {
+        PoolKind::Naive => {
+            run::<NaiveThreadPool>(&server, engine, dir, n, limits, group_commit, import)
+        }
+        PoolKind::SharedQueue => {
+            run::<SharedQueueThreadPool>(&server, engine, dir, n, limits, group_commit, import)
+        }
+        PoolKind::Rayon => {
+            run::<RayonThreadPool>(&server, engine, dir, n, limits, group_commit, import)
+        }
+    }
+}
 
+/// Open the engine and serve it. With `group_commit` the server syncs the writes itself,
+/// so the engine needn't flush every one.
+fn run<P: ThreadPool>(
+    server: &KvServer,
+    engine: &str,
+    data_dir: &Path,
+    worker_num: u32,
+    limits: Limits,
+    group_commit: bool,
+    import: Option<(PathBuf, &str)>,
+) -> Result<()> {
+    let pool = P::new(worker_num)?;
     if engine == "kvs" {
-        server.start(ip, KvStore::open(current_dir()?)?, pool)?;
-    } else if engine == "sled" {
-        server.start(ip, SledStore::open(current_dir()?)?, pool)?;
+        let store = KvStore::open(data_dir)?.with_limits(limits);
+        import_into(&store, data_dir, import)?;
+        server.start(store, pool)
+    } else {
+        // the server syncs every write it answers, the flusher only covers the import
+        let flush_every = group_commit.then_some(SLED_FLUSH_EVERY);
+        let store = SledStore::open_with_options(data_dir, flush_every)?.with_limits(limits);
+        import_into(&store, data_dir, import)?;
+        server.start(store, pool)
+    }
+}
+
+/// The directory and engine given by `--import-from` and `--import-engine`, if any.
+fn import_source(matches: &ArgMatches) -> Result<Option<(PathBuf, &'static str)>> {
+    let Some(dir) = matches.get_one::<PathBuf>("import-from") else {
+        return Ok(None);
+    };
+    // opening a store in a directory without data would create some
+    let found = detect_engine(dir);
+    match (found, matches.get_one::<String>("import-engine")) {
+        (Some(found), Some(expected)) if found != expected => Err(KvsError::EngineMismatch {
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }),
+        (Some(found), _) => Ok(Some((dir.clone(), found))),
+        (None, expected) => Err(KvsError::EngineMismatch {
+            expected: expected.map_or("kvs or sled", |engine| engine).to_string(),
+            found: "no data".to_string(),
+        }),
     }
+}
 
+/// Copy every entry of the `import` source, a directory and its engine, into `store`,
+/// then mark the import in `data_dir` as complete.
+fn import_into(
+    store: &impl KvsEngine,
+    data_dir: &Path,
+    import: Option<(PathBuf, &str)>,
+) -> Result<()> {
+    let Some((dir, engine)) = import else {
+        return Ok(());
+    };
+    let copied = match engine {
+        "kvs" => transfer(KvStore::open(&dir)?, store)?,
+        _ => transfer(SledStore::open(&dir)?, store)?,
+    };
+    store.sync()?;
+    std::fs::remove_file(data_dir.join(IMPORT_MARKER))?;
+    info!("Imported {copied} keys from {engine} in {}", dir.display());
     Ok(())
 }
 
+/// Copy the data of one engine into a new directory with another, for the `migrate`
+/// subcommand.
+fn migrate(matches: &ArgMatches) -> Result<()> {
+    let from = matches.get_one::<String>("from").expect("required");
+    let to = matches.get_one::<String>("to").expect("required");
+    let source_dir = match matches.get_one::<PathBuf>("data-dir") {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
+    let dest_dir = matches.get_one::<PathBuf>("dest").expect("required");
+
+    // opening a store in a directory without data would create some
+    let found = detect_engine(&source_dir);
+    if found != Some(from.as_str()) {
+        return Err(KvsError::EngineMismatch {
+            expected: from.to_string(),
+            found: found.unwrap_or("no data").to_string(),
+        });
+    }
+    std::fs::create_dir_all(dest_dir)?;
+    let config_path = dest_dir.join("config.json");
+    match detect_engine(dest_dir) {
+        Some(found) if found != to => {
+            return Err(KvsError::EngineMismatch {
+                expected: to.to_string(),
+                found: found.to_string(),
+            })
+        }
+        Some(_) if !matches.get_flag("force") => {
+            return Err(KvsError::DataExists(dest_dir.clone()))
+        }
+        None if config_path.exists() && !matches.get_flag("force") => {
+            return Err(KvsError::DataExists(dest_dir.clone()))
+        }
+        _ => {}
+    }
+
+    let copied = match (from.as_str(), to.as_str()) {
+        ("kvs", "sled") => transfer(KvStore::open(&source_dir)?, &SledStore::open(dest_dir)?)?,
+        ("sled", "kvs") => transfer(SledStore::open(&source_dir)?, &KvStore::open(dest_dir)?)?,
+        ("kvs", _) => transfer(KvStore::open(&source_dir)?, &KvStore::open(dest_dir)?)?,
+        _ => transfer(SledStore::open(&source_dir)?, &SledStore::open(dest_dir)?)?,
+    };
+
+    let source_config = source_dir.join("config.json");
+    let mut config = if source_config.exists() {
+        ServerConfig::load(&source_config)?
+    } else {
+        ServerConfig::new(to.to_string())
+    };
+    config.engine = to.to_string();
+    config.data_dir = dest_dir.clone();
+    config.save(&config_path)?;
+    info!(
+        "Copied {copied} keys from {from} to {to} in {}",
+        dest_dir.display()
+    );
+    Ok(())
+}
+
+/// Stream every entry of `source` into `target` through a pipe, so the data is never all
+/// in memory. Returns the number of entries copied.
+fn transfer(source: impl KvsEngine, target: &impl KvsEngine) -> Result<u64> {
+    let (reader, writer) = io::pipe()?;
+    let exporter = thread::spawn(move || source.export(writer));
+    // a failed import drops the reader, which fails the export's next write too
+    let imported = target.import(reader);
+    let exported = exporter.join().expect("export thread panicked");
+    let imported = imported?;
+    exported?;
+    Ok(imported)
+}
+
+/// Infer the engine from the data files persisted in `dir`, if any.
+fn detect_engine(dir: &Path) -> Option<&'static str> {
+    if dir.join("log").is_file() {
+        Some("kvs")
+    } else if dir.join("conf").is_file() && dir.join("db").is_file() {
+        Some("sled")
+    } else {
+        None
+    }
+}
+
 // rust error handling
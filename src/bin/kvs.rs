@@ -1,5 +1,6 @@
-use clap::{Arg, Command as cCommand};
+use clap::{arg, value_parser, Arg, ArgMatches, Command as cCommand};
 use kvs::{KvStore, KvsEngine, Result};
+use std::path::PathBuf;
 use std::{env::current_dir, process::exit};
 use stderrlog::{self, LogLevelNum, Timestamp};
 
@@ -35,11 +36,21 @@ fn main() -> Result<()> {
                     .required(true),
             ),
         )
+        .subcommand(
+            cCommand::new("compact")
+                .about("Rewrite the log without stale records and print the bytes reclaimed"),
+        )
+        .arg(
+            arg!(--"data-dir" <DIR> "Directory holding the log, created if missing. \
+                Defaults to the working directory")
+            .value_parser(value_parser!(PathBuf))
+            .global(true),
+        )
         .get_matches();
 
     match matches.subcommand() {
         Some(("set", _matches)) => {
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = open(_matches)?;
             store.set(
                 _matches
                     .get_one::<String>("KEY")
@@ -53,7 +64,7 @@ fn main() -> Result<()> {
             //println!("Set successfully");
         }
         Some(("get", _matches)) => {
-            let mut store = KvStore::open(current_dir()?)?;
+            let store = open(_matches)?;
             match store.get(
                 _matches
                     .get_one::<String>("KEY")
@@ -69,31 +80,49 @@ fn main() -> Result<()> {
             }
         }
         Some(("rm", _matches)) => {
-            let mut store = KvStore::open(current_dir()?)?;
-            match store.remove(
-                _matches
-                    .get_one::<String>("KEY")
-                    .expect("required")
-                    .to_string(),
-            ) {
-                Ok(_) => {}
-                Err(_) => {
-                    println!("Key not found");
-                    exit(1);
-                }
+            let store = open(_matches)?;
+            if store
+                .remove_opt(
+                    _matches
+                        .get_one::<String>("KEY")
+                        .expect("required")
+                        .to_string(),
+                )?
+                .is_none()
+            {
+                println!("Key not found");
+                exit(1);
             }
         }
+        Some(("compact", _matches)) => {
+            let store = open(_matches)?;
+            println!("{}", store.compact()?);
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
 
+/// Open the store in the subcommand's `--data-dir`, or in the working directory.
+fn open(matches: &ArgMatches) -> Result<KvStore> {
+    let dir = match matches.get_one::<PathBuf>("data-dir") {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
+    std::fs::create_dir_all(&dir)?;
+    KvStore::open(dir)
+}
+
+#[cfg(test)]
 use assert_cmd::prelude::*;
+#[cfg(test)]
 use predicates::ord::eq;
+#[cfg(test)]
 use predicates::str::{contains, is_empty, PredicateStrExt};
+#[cfg(test)]
 use std::process::Command;
+#[cfg(test)]
 use tempfile::TempDir;
-use walkdir::WalkDir;
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
@@ -106,7 +135,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -117,7 +146,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -130,7 +159,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -143,25 +172,52 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 }
 
+// `--data-dir` should keep the log out of the working directory
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+    let work_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&work_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("--data-dir")
+        .arg(&data_dir)
+        .args(["get", "key1"])
+        .current_dir(&work_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    assert!(data_dir.join("log").is_file());
+    assert!(!work_dir.path().join("log").exists());
+}
+
 #[test]
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -169,7 +225,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -182,13 +238,13 @@ fn cli_get_stored() -> Result<()> {
 #[test]
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -196,7 +252,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -205,17 +261,53 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs compact` should print the bytes reclaimed and shrink the log to match.
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{i}"))?;
+    }
+    drop(store);
+    let log_path = temp_dir.path().join("log");
+    let before = std::fs::metadata(&log_path)?.len();
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    let reclaimed: u64 = String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .expect("compact prints a byte count");
+    let after = std::fs::metadata(&log_path)?.len();
+    assert!(after < before);
+    assert_eq!(before - after, reclaimed);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value99").trim());
+    Ok(())
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -224,19 +316,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -245,13 +337,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -260,7 +352,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct CacheEntry {
+    value: Option<String>,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// `last_used -> key`, oldest first, for picking the entry to evict.
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    /// Bumped by every invalidation, so a fill can tell a write raced its engine read.
+    epoch: u64,
+}
+
+/// LRU cache of `get` results with a per-entry time to live.
+///
+/// Misses are cached too, as `None`. A fill takes the epoch from `begin_read` before
+/// reading the engine and is dropped if any key was invalidated in between, so a write
+/// that lands during the read can't be shadowed by the value it replaced.
+pub struct ReadCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize, ttl: Duration) -> ReadCache {
+        ReadCache {
+            capacity,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// The cached result for `key`, or `None` if it isn't cached or has expired.
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        let mut state = self.state.lock().unwrap();
+        let expires = state.entries.get(key)?.expires;
+        if expires <= Instant::now() {
+            state.remove(key);
+            return None;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        let entry = state.entries.get_mut(key).unwrap();
+        let last_used = std::mem::replace(&mut entry.last_used, tick);
+        let value = entry.value.clone();
+        state.lru.remove(&last_used);
+        state.lru.insert(tick, key.to_owned());
+        Some(value)
+    }
+
+    /// Epoch to pass to `fill` for an engine read that starts now.
+    pub fn begin_read(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// Cache the result of an engine read started at `epoch`.
+    pub fn fill(&self, key: String, value: Option<String>, epoch: u64) {
+        let mut state = self.state.lock().unwrap();
+        if self.capacity == 0 || state.epoch != epoch {
+            return;
+        }
+        state.remove(&key);
+        if state.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = state.lru.pop_first() {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.lru.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                expires: Instant::now() + self.ttl,
+                last_used: tick,
+            },
+        );
+    }
+
+    /// Drop `key` after a write to it. Call once the write has reached the engine.
+    pub fn invalidate(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.remove(key);
+    }
+
+    /// Drop every entry after a write that may have changed any key.
+    pub fn invalidate_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.entries.clear();
+        state.lru.clear();
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use crate::engines::{export_entries, import_entries};
+use crate::proto::{read_frame, write_frame, KeyResult, Request, Response};
+use crate::{KvsError, Result, Stats, WriteBatch};
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// Entries fetched per scan request by `KvsClient::export`.
+const EXPORT_PAGE: usize = 1000;
+
+/// Client for one kvs server, sending every request over a single connection.
+///
+/// The server closes connections left idle past its `idle_timeout_ms`, after which
+/// requests fail with an I/O error; connect again to go on.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let socket = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(socket.try_clone()?),
+            writer: BufWriter::new(socket),
+        })
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Get { key })? {
+            Response::Ok(value) => Ok(value),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Values of `keys` in one request, in the same order, with the status of each key.
+    /// Keys the server fails to read come back as `KeyResult::Error` rather than failing
+    /// the call.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<KeyResult>> {
+        match self.request(Request::GetMany { keys })? {
+            Response::Values(values) => Ok(values),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Whether `key` has a value, without transferring the value.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        match self.request(Request::Exists { key })? {
+            Response::Exists(exists) => Ok(exists),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Set `key` to `value`, returning the value it replaced.
+    pub fn set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        match self.request(Request::Set { key, value })? {
+            Response::Ok(previous) => Ok(previous),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Remove `key` and return its value, failing with `KvsError::KeyNotFound` if it
+    /// doesn't exist.
+    pub fn remove(&mut self, key: String) -> Result<String> {
+        match self.request(Request::Remove { key })? {
+            Response::Ok(Some(previous)) => Ok(previous),
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Remove every key on the server.
+    pub fn clear(&mut self) -> Result<()> {
+        match self.request(Request::Clear)? {
+            Response::Ok(None) => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Set `key` to `new`, or remove it if `new` is `None`, if its current value is
+    /// `expected`. Returns whether the swap happened.
+    pub fn cas(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        match self.request(Request::Cas { key, expected, new })? {
+            Response::Swapped(swapped) => Ok(swapped),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Apply `batch` on the server in one request.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        match self.request(Request::Batch { batch })? {
+            Response::Ok(None) => Ok(()),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Up to `limit` entries after `after` in key order, from the first key if `after`
+    /// is `None`.
+    pub fn scan(
+        &mut self,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        match self.request(Request::Scan { after, limit })? {
+            Response::Entries(entries) => Ok(entries),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    /// Write every entry on the server to `w` in the format of `KvsEngine::export`,
+    /// fetching a page of entries at a time. Returns the number of entries written.
+    ///
+    /// Pages are separate requests, so writes made during the export may or may not be
+    /// in it, but keys that exist throughout are written exactly once.
+    pub fn export(&mut self, w: impl Write) -> Result<u64> {
+        let mut after = None;
+        let mut page = Vec::<(String, String)>::new().into_iter();
+        let entries = std::iter::from_fn(|| loop {
+            if let Some((key, value)) = page.next() {
+                after = Some(key.clone());
+                return Some(Ok((key, value)));
+            }
+            match self.scan(after.clone(), Some(EXPORT_PAGE)) {
+                Ok(next) if next.is_empty() => return None,
+                Ok(next) => page = next.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        });
+        export_entries(w, entries)
+    }
+
+    /// Set every entry of an export read from `r` on the server, one batch request at a
+    /// time. Returns the number of entries read.
+    pub fn import(&mut self, r: impl Read) -> Result<u64> {
+        import_entries(r, |batch| self.write_batch(batch))
+    }
+
+    /// Key count and disk usage of the server's engine.
+    pub fn stats(&mut self) -> Result<Stats> {
+        match self.request(Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            _ => Err(KvsError::UnexpectedResponse),
+        }
+    }
+
+    fn request(&mut self, request: Request) -> Result<Response> {
+        write_frame(&mut self.writer, &request)?;
+        match read_frame(&mut self.reader)? {
+            Response::Err(msg) => Err(KvsError::Server(msg)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// Virtual nodes per server on the hash ring.
+const VIRTUAL_NODES: usize = 64;
+
+/// Client spreading keys over several servers with consistent hashing.
+///
+/// Each server owns `VIRTUAL_NODES` points on a hash ring, and a key belongs to the
+/// first point at or after its hash. Adding or removing a server only moves the keys
+/// next to its points. When a server is down, operations on its keys fail instead of
+/// being rerouted, so two servers never both think they own a key. Connections are
+/// opened on first use and reopened after an I/O error.
+pub struct ShardedKvsClient {
+    addrs: Vec<String>,
+    ring: BTreeMap<u64, usize>,
+    clients: Vec<Option<KvsClient>>,
+}
+
+impl ShardedKvsClient {
+    pub fn new(addrs: Vec<String>) -> ShardedKvsClient {
+        assert!(
+            !addrs.is_empty(),
+            "ShardedKvsClient needs at least one server"
+        );
+        let mut ring = BTreeMap::new();
+        for (shard, addr) in addrs.iter().enumerate() {
+            for node in 0..VIRTUAL_NODES {
+                ring.insert(hash(format!("{addr}#{node}").as_bytes()), shard);
+            }
+        }
+        let clients = addrs.iter().map(|_| None).collect();
+        ShardedKvsClient {
+            addrs,
+            ring,
+            clients,
+        }
+    }
+
+    /// Address of the server that owns `key`.
+    pub fn server_for(&self, key: &str) -> &str {
+        &self.addrs[self.shard_for(key)]
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let shard = self.shard_for(&key);
+        self.with_shard(shard, |client| client.get(key))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let shard = self.shard_for(&key);
+        self.with_shard(shard, |client| client.set(key, value))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<String> {
+        let shard = self.shard_for(&key);
+        self.with_shard(shard, |client| client.remove(key))
+    }
+
+    pub fn cas(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let shard = self.shard_for(&key);
+        self.with_shard(shard, |client| client.cas(key, expected, new))
+    }
+
+    /// Scan every server and merge the results in key order. Fails if any server does.
+    pub fn scan(
+        &mut self,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        for shard in 0..self.addrs.len() {
+            let after = after.clone();
+            entries.extend(self.with_shard(shard, |client| client.scan(after, limit))?);
+        }
+        // each server's entries are sorted and keys live on one server only
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        entries.truncate(limit.unwrap_or(usize::MAX));
+        Ok(entries)
+    }
+
+    fn shard_for(&self, key: &str) -> usize {
+        let hash = hash(key.as_bytes());
+        match self.ring.range(hash..).next() {
+            Some((_, shard)) => *shard,
+            // wrap around to the first point on the ring
+            None => *self.ring.values().next().unwrap(),
+        }
+    }
+
+    fn with_shard<T>(
+        &mut self,
+        shard: usize,
+        f: impl FnOnce(&mut KvsClient) -> Result<T>,
+    ) -> Result<T> {
+        if self.clients[shard].is_none() {
+            self.clients[shard] = Some(KvsClient::connect(&self.addrs[shard])?);
+        }
+        let result = f(self.clients[shard].as_mut().unwrap());
+        if let Err(KvsError::Io(_)) = result {
+            // the connection may be broken, open a fresh one next time
+            self.clients[shard] = None;
+        }
+        result
+    }
+}
+
+/// 64-bit FNV-1a followed by MurmurHash3's finalizer, which spreads keys that differ
+/// only in their last bytes. Unlike `DefaultHasher` it is stable across builds, so every
+/// client places keys on the same servers.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,55 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A fixed-size bloom filter over string keys.
+///
+/// Bits are set with atomic `fetch_or`, so inserts and lookups can run concurrently
+/// through a shared reference. Keys can't be removed; rebuild the filter instead.
+pub struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Size the filter for `expected_keys` keys at the given false-positive rate.
+    pub fn new(expected_keys: usize, fp_rate: f64) -> BloomFilter {
+        let n = expected_keys.max(1) as f64;
+        let p = fp_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-n * p.ln() / (ln2 * ln2)).ceil().max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / n) * ln2).round().clamp(1.0, 30.0) as u32;
+        let words = num_bits.div_ceil(64) as usize;
+        BloomFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            num_bits: words as u64 * 64,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&self, key: &str) {
+        for bit in self.bit_indexes(key) {
+            self.bits[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// `false` means the key was definitely never inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_indexes(key).all(|bit| {
+            self.bits[(bit / 64) as usize].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0
+        })
+    }
+
+    /// Double hashing: the i-th probe is `h1 + i * h2`.
+    fn bit_indexes(&self, key: &str) -> impl Iterator<Item = u64> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        // a second, independent hash from the same state
+        0xb5ad_4ece_da1c_e2a9u64.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
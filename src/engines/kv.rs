@@ -1,18 +1,19 @@
-use crate::{KvsEngine, Result};
+use crate::engines::bloom::BloomFilter;
+use crate::engines::{Cursor, Limits, Stats, WriteBatch};
+use crate::{KvsEngine, KvsError, Result};
 use dashmap::DashMap;
-use log::{debug, info};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex, MutexGuard, RwLock};
-use std::thread;
-
-#[derive(Debug)]
-pub enum KVSError {
-    NoSuchKey,
-    WriteLogFail,
-}
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 enum Command {
@@ -25,207 +26,1091 @@ struct Record {
     cmd: Command,
     key: String,
     value: String,
+    /// Milliseconds since the Unix epoch after which a set no longer applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// Format version written before every record, and in the log header.
+///
+/// Records from before versioning start straight with their JSON `{`, so versions must
+/// stay below `b'{'` to be told apart from them.
+const RECORD_VERSION: u8 = 1;
+
+/// Start of the first line of a versioned log, followed by the version byte.
+const LOG_MAGIC: &[u8] = b"kvslog";
+
+impl Record {
+    /// `RECORD_VERSION`, the record as JSON, then a newline.
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![RECORD_VERSION];
+        serde_json::to_writer(&mut buf, self)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+
+    /// Decode a line of the log starting at offset `pos`.
+    fn decode(line: &str, pos: u64) -> Result<Record> {
+        match line.as_bytes().first() {
+            // written before records were versioned
+            Some(b'{') => Ok(serde_json::from_str(line)?),
+            Some(&RECORD_VERSION) => Ok(serde_json::from_str(&line[1..])?),
+            Some(version) => Err(unknown_version(*version, pos)),
+            None => Err(KvsError::Corrupt(format!(
+                "Empty record at log offset {pos}"
+            ))),
+        }
+    }
+}
+
+fn unknown_version(version: u8, pos: u64) -> KvsError {
+    KvsError::IncompatibleFormat(format!(
+        "log format version {version} at offset {pos}, this build reads up to version \
+         {RECORD_VERSION}"
+    ))
+}
+
+fn log_header() -> Vec<u8> {
+    [LOG_MAGIC, &[RECORD_VERSION, b'\n']].concat()
 }
 
+/// Whether the last `line` of the log was cut short: it lacks its newline or doesn't
+/// parse. A record from a newer format version is not torn and still fails the open.
+fn is_torn(line: &[u8], decoded: &Result<Record>) -> bool {
+    !line.ends_with(b"\n") || matches!(decoded, Err(KvsError::Serde(_) | KvsError::Corrupt(_)))
+}
+
+/// Number of mutations retained by the in-memory change feed.
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// A mutation observed through [`KvStore::changes_since`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    Set {
+        seq: u64,
+        key: String,
+        value: String,
+    },
+    Remove {
+        seq: u64,
+        key: String,
+    },
+}
+
+impl ChangeEvent {
+    pub fn seq(&self) -> u64 {
+        match self {
+            ChangeEvent::Set { seq, .. } | ChangeEvent::Remove { seq, .. } => *seq,
+        }
+    }
+}
+
+/// Keys the negative-lookup filter is sized for when none is given to `open_with_bloom`.
+pub const DEFAULT_BLOOM_KEYS: usize = 1 << 16;
+/// False-positive rate of the negative-lookup filter when none is given to `open_with_bloom`.
+pub const DEFAULT_BLOOM_FP_RATE: f64 = 0.01;
+
+/// Bounded ring of the most recent mutations, numbered from 1 since the store was opened.
+#[derive(Default)]
+struct ChangeFeed {
+    seq: u64,
+    events: VecDeque<ChangeEvent>,
+}
+
+/// Where a key's latest record sits in the log.
+#[derive(Clone, Copy, Debug)]
+struct LogPointer {
+    pos: u64,
+    len: u64,
+    /// Copied from the record, so expiry is checked without reading the log.
+    expires_at: Option<u64>,
+}
+
+impl LogPointer {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Wall clock time in milliseconds since the Unix epoch, which is how expiry persists.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Bytes of overwritten and removed records after which a write triggers `compact`, when
+/// none is given to `open_with_options`.
+pub const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Capacity of the log writer's buffer when none is given to `open_with_options`.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Tuning for [`KvStore::open_with_options`]. `KvOptions::default()` is what `open` uses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KvOptions {
+    /// Bytes of overwritten and removed records after which a write triggers `compact`.
+    pub compaction_threshold: u64,
+    /// `fsync` the log after every write, so acknowledged writes survive a power failure
+    /// and not only a crash of the process. Writes reach the OS before returning either
+    /// way, which is all readers need.
+    pub flush_on_write: bool,
+    /// Capacity of the log writer's buffer. Records larger than it skip the buffer.
+    pub buffer_size: usize,
+    /// Leave records in the writer's buffer and write them out every interval, or sooner
+    /// once `buffer_size` bytes are waiting, instead of on every write. A crash of the
+    /// process loses up to an interval of acknowledged writes; `KvStore::sync` writes out
+    /// everything buffered so far.
+    pub flush_every: Option<Duration>,
+    /// `fsync` the log every interval instead of after every write, so a power failure
+    /// loses at most an interval of acknowledged writes. Has no effect with
+    /// `flush_on_write`, which already syncs every write.
+    pub flush_interval: Option<Duration>,
+    /// Keys the negative-lookup bloom filter is sized for.
+    pub bloom_keys: usize,
+    /// False-positive rate of the bloom filter at `bloom_keys` keys.
+    pub bloom_fp_rate: f64,
+}
+
+impl Default for KvOptions {
+    fn default() -> KvOptions {
+        KvOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            flush_on_write: false,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            flush_every: None,
+            flush_interval: None,
+            bloom_keys: DEFAULT_BLOOM_KEYS,
+            bloom_fp_rate: DEFAULT_BLOOM_FP_RATE,
+        }
+    }
+}
+
+/// The index and the log file its offsets point into.
+///
+/// Compaction swaps both at once, so a reader holding a snapshot always reads the file
+/// its offsets were taken from, even after that file has been replaced on disk.
+#[derive(Clone)]
+struct LogState {
+    index: Arc<DashMap<String, LogPointer>>,
+    file: Arc<File>,
+}
+
+/// Log-structured store with an in-memory `key -> offset` index.
+///
+/// Writes are appended by a single writer under `log_writer`. Reads use positioned reads
+/// on a shared file handle, so concurrent `get`s don't contend on any lock except the
+/// brief read lock taken to snapshot `state`. A read of a record still in the writer's
+/// buffer, which only happens with `KvOptions::flush_every`, writes the buffer out first.
 #[derive(Clone)]
 pub struct KvStore {
-    kv: Arc<DashMap<String, u64>>,
+    state: Arc<RwLock<LogState>>,
     path: Arc<PathBuf>,
     log_writer: Arc<Mutex<BufWriterWithPos<File>>>,
-    reader: Arc<Mutex<BufReaderWithPos<File>>>,
+    /// Log offset up to which records have been handed to the OS, and so are readable.
+    flushed: Arc<AtomicU64>,
+    /// Log offset up to which records are known to be on disk.
+    synced: Arc<AtomicU64>,
+    /// Only held so the last handle stops the flusher.
+    _flusher: Option<Arc<Flusher>>,
+    changes: Arc<Mutex<ChangeFeed>>,
+    filter: Arc<RwLock<BloomFilter>>,
+    options: KvOptions,
+    filtered_gets: Arc<AtomicU64>,
+    /// Bytes in the log that no longer back a live key.
+    stale_bytes: Arc<AtomicU64>,
+    limits: Limits,
     // compact_daemon: Arc<Mutex<thread::JoinHandle<()>>>,
 }
 
 impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let record = serde_json::to_string(&Record {
-            cmd: Command::Set,
-            key: key.clone(),
-            value: value,
-        })? + "\n";
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
         let mut guard = self.log_writer.lock().unwrap();
-        let n = guard.write(record.as_bytes())?;
-        let pos = guard.pos - n as u64;
-        guard.flush()?;
+        let previous = self.append_set(&mut guard, &key, &value, None)?;
         drop(guard);
-        if n != record.as_bytes().len() {
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                "Not written enough bytes and corrupted file",
-            ));
+        self.record_change(key, Some(value));
+        self.maybe_compact()?;
+        Ok(previous)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.lookup(&key, None)
+    }
+
+    /// Answered from the bloom filter and the index, without reading the log.
+    fn contains(&self, key: String) -> Result<bool> {
+        if !self.filter.read().unwrap().may_contain(&key) {
+            self.filtered_gets.fetch_add(1, Ordering::Relaxed);
+            return Ok(false);
         }
-        if self.kv.contains_key(&key) {
-            self.kv.remove(&key);
+        // removed keys are unindexed, so only expiry is left to check
+        let state = self.state.read().unwrap();
+        let pointer = match state.index.get(&key) {
+            None => return Ok(false),
+            Some(pointer) => *pointer,
+        };
+        if pointer.expired(now_millis()) {
+            self.drop_expired(&state.index, &key, pointer);
+            return Ok(false);
         }
-        self.kv.insert(key.clone(), pos);
-        debug!("Inserted: key: {key}, value: {pos}");
-        Ok(())
+        Ok(true)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        match self.kv.get(&key).as_deref() {
-            None => Ok(None),
-            Some(pos) => {
-                let mut value = String::new();
-                let mut guard = self.reader.lock().unwrap();
-                guard.seek(SeekFrom::Start(*pos))?;
-                guard.read_line(&mut value)?;
-                drop(guard);
-                let record: Record = serde_json::from_str(&value)?;
-                if record.cmd == Command::Remove {
-                    Ok(None)
-                } else {
-                    Ok(Some(record.value))
+    /// Counts keys set with a TTL that expired but haven't been dropped yet.
+    fn approx_key_count(&self) -> Result<u64> {
+        Ok(self.state.read().unwrap().index.len() as u64)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        Ok(KvStore::stats(self))
+    }
+
+    /// The index isn't ordered, so this sorts a snapshot of the keys after `after`. Values
+    /// are read as the cursor advances, and keys removed by then are skipped.
+    fn cursor(&self, after: Option<String>) -> Result<Cursor> {
+        let mut keys: Vec<String> = self
+            .state()
+            .index
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| after.as_ref().is_none_or(|after| key > after))
+            .collect();
+        keys.sort_unstable();
+        let store = self.clone();
+        Ok(Cursor::new(keys.into_iter().filter_map(
+            move |key| match store.get(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            },
+        )))
+    }
+
+    /// Reads every value from one snapshot of the index and log, so the result is a
+    /// consistent view even if writes or compaction run meanwhile.
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        self.snapshot(start.as_ref(), end.as_ref()).collect()
+    }
+
+    /// The batch is appended with one write and one flush under the writer lock, and
+    /// indexed while readers are held off, so a `get` sees all of it or none of it.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.limits.check_batch(&batch)?;
+        let mut guard = self.log_writer.lock().unwrap();
+        let index = self.state().index;
+        // whether each key exists once the batch's earlier records are applied
+        let mut present: HashMap<&str, bool> = HashMap::new();
+        let mut buf = Vec::new();
+        let mut applied = Vec::new();
+        for record in batch.records() {
+            let cmd = match record.cmd {
+                crate::Command::Set => Command::Set,
+                crate::Command::Remove => Command::Remove,
+                _ => unreachable!("WriteBatch only holds sets and removes"),
+            };
+            let exists = match present.get(record.key.as_str()) {
+                Some(exists) => *exists,
+                None => index.contains_key(&record.key),
+            };
+            if cmd == Command::Remove && !exists {
+                continue;
+            }
+            present.insert(&record.key, cmd == Command::Set);
+            let encoded = Record {
+                cmd,
+                key: record.key.clone(),
+                value: record.value.clone(),
+                expires_at: None,
+            }
+            .encode()?;
+            let pointer = LogPointer {
+                pos: guard.pos + buf.len() as u64,
+                len: encoded.len() as u64,
+                expires_at: None,
+            };
+            buf.extend_from_slice(&encoded);
+            applied.push((record, pointer));
+        }
+        guard.write_all(&buf)?;
+        self.flush_log(&mut guard)?;
+
+        let filter = self.filter.read().unwrap();
+        for (record, _) in &applied {
+            if record.cmd == crate::Command::Set {
+                filter.insert(&record.key);
+            }
+        }
+        drop(filter);
+        let readers = self.state.write().unwrap();
+        let mut stale = 0;
+        for (record, pointer) in &applied {
+            if record.cmd == crate::Command::Set {
+                if let Some(old) = index.insert(record.key.clone(), *pointer) {
+                    stale += old.len;
                 }
+            } else if let Some((_, old)) = index.remove(&record.key) {
+                stale += old.len + pointer.len;
             }
         }
+        drop(readers);
+        self.stale_bytes.fetch_add(stale, Ordering::SeqCst);
+        drop(guard);
+
+        for (record, _) in applied {
+            let value = (record.cmd == crate::Command::Set).then(|| record.value.clone());
+            self.record_change(record.key.clone(), value);
+        }
+        self.maybe_compact()
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        if self.kv.contains_key(&key) {
-            let record = serde_json::to_string(&Record {
-                cmd: Command::Remove,
-                key: key.clone(),
-                value: "".to_owned(),
-            })? + "\n";
-            let mut guard = self.log_writer.lock().unwrap();
-            let n = guard.write(record.as_bytes())?;
-            guard.flush()?;
-            drop(guard);
-            if n != record.as_bytes().len() {
-                return Err(std::io::Error::new(
-                    ErrorKind::Other,
-                    "Not written enough bytes and corrupted file",
-                ));
+    /// Writes out every buffered record and waits until the whole log is on disk.
+    ///
+    /// Acknowledged writes already survive a crash of the process unless the store was
+    /// opened with `KvOptions::flush_every`, and a power failure only with
+    /// `KvOptions::flush_on_write`. After `sync` returns, every write acknowledged before
+    /// it survives both.
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.log_writer.lock().unwrap();
+        write_out(&mut writer, &self.flushed, Some(&self.synced))?;
+        Ok(())
+    }
+
+    /// Swaps in an empty log, like a compaction that finds nothing live. Reads that
+    /// already took a snapshot finish against the old file.
+    fn clear(&self) -> Result<()> {
+        let mut writer = self.log_writer.lock().unwrap();
+        // snapshots of the old log may still point into the buffer
+        writer.flush()?;
+        let temp_path = self.path.with_file_name("log.temp");
+        let mut empty = BufWriterWithPos::new(File::create(&temp_path)?, self.options.buffer_size)?;
+        empty.write_all(&log_header())?;
+        let old = self.replace_log(&mut writer, empty, DashMap::new())?;
+        drop(writer);
+
+        let mut keys: Vec<String> = old.index.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort_unstable();
+        for key in keys {
+            self.record_change(key, None);
+        }
+        Ok(())
+    }
+
+    fn remove_opt(&self, key: String) -> Result<Option<String>> {
+        let mut guard = self.log_writer.lock().unwrap();
+        let Some(previous) = self.append_remove(&mut guard, &key)? else {
+            return Ok(None);
+        };
+        drop(guard);
+        self.record_change(key, None);
+        self.maybe_compact()?;
+        Ok(Some(previous))
+    }
+
+    /// Holds the writer lock from reading the current value until the new record is
+    /// indexed, so no other write to any key can land in between.
+    fn cas(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let mut guard = self.log_writer.lock().unwrap();
+        if self.lookup(&key, Some(&mut guard))? != expected {
+            return Ok(false);
+        }
+        match &new {
+            Some(value) => {
+                self.append_set(&mut guard, &key, value, None)?;
+            }
+            None => {
+                if self.append_remove(&mut guard, &key)?.is_none() {
+                    // already absent, as expected
+                    return Ok(true);
+                }
             }
-            self.kv.remove(&key);
-            Ok(())
-        } else {
-            Err(std::io::Error::new(ErrorKind::Other, "Non existent key"))
         }
+        drop(guard);
+        self.record_change(key, new);
+        self.maybe_compact()?;
+        Ok(true)
     }
 }
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvOptions::default())
+    }
+
+    /// Like `open`, but sizes the negative-lookup bloom filter for `expected_keys` keys
+    /// at `fp_rate` false positives.
+    ///
+    /// The filter only lets `get` answer `None` for keys that were never set without
+    /// touching the index; it is an optimization, not a source of truth. Removed keys
+    /// stay in the filter until the next compaction rebuilds it.
+    pub fn open_with_bloom(
+        path: impl Into<PathBuf>,
+        expected_keys: usize,
+        fp_rate: f64,
+    ) -> Result<KvStore> {
+        let options = KvOptions {
+            bloom_keys: expected_keys,
+            bloom_fp_rate: fp_rate,
+            ..KvOptions::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Open the store in `path` tuned by `options`.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvOptions) -> Result<KvStore> {
         let p: PathBuf = path.into().join("log");
-        let mut kv = DashMap::<String, u64>::new();
+        let kv = DashMap::<String, LogPointer>::new();
+        let mut stale = 0;
+        let now = now_millis();
         let f = std::fs::OpenOptions::new()
             .read(true)
             .append(true)
             .create(true)
             .truncate(false)
             .open(&p)?;
-        let writer = BufWriterWithPos::new(f)?;
+        let mut writer = BufWriterWithPos::new(f, options.buffer_size)?;
+        if writer.pos == 0 {
+            writer.write_all(&log_header())?;
+            writer.flush()?;
+        }
         let mut reader = BufReader::new(File::open(&p)?);
-        let mut pos: u64 = 0;
         let end = reader.seek(SeekFrom::End(0))?;
-        reader.seek(SeekFrom::Start(pos))?;
+        reader.seek(SeekFrom::Start(0))?;
+        // logs written before versioning have no header and start with a record
+        let mut pos: u64 = 0;
+        if reader.fill_buf()?.starts_with(LOG_MAGIC) {
+            let mut header = Vec::new();
+            reader.read_until(b'\n', &mut header)?;
+            match header.get(LOG_MAGIC.len()) {
+                Some(&version) if version <= RECORD_VERSION => {}
+                Some(&version) => return Err(unknown_version(version, 0)),
+                None => return Err(KvsError::Corrupt("Truncated log header".to_owned())),
+            }
+            pos = header.len() as u64;
+        }
         while pos < end {
-            let mut cmd = String::new();
-            let x = reader.read_line(&mut cmd)?;
-            let record: Record = serde_json::from_str(&cmd)?;
+            let mut line = Vec::new();
+            let x = reader.read_until(b'\n', &mut line)?;
+            if x == 0 {
+                // the log shrank underneath us, don't spin on an empty read
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("Log ended at offset {pos}, expected {end} bytes"),
+                )
+                .into());
+            }
+            let decoded = std::str::from_utf8(&line)
+                .map_err(|e| KvsError::Corrupt(format!("Record at log offset {pos}: {e}")))
+                .and_then(|line| Record::decode(line, pos));
+            // a crash mid-append can only tear the last record, which was never acknowledged
+            if pos + x as u64 == end && is_torn(&line, &decoded) {
+                warn!(
+                    "Dropping {x} bytes of a torn record at log offset {pos}, the end of {}",
+                    p.display()
+                );
+                writer.writer.get_ref().set_len(pos)?;
+                writer.seek(SeekFrom::End(0))?;
+                break;
+            }
+            let record = decoded?;
+            let len = x as u64;
             match record.cmd {
                 Command::Remove => {
-                    kv.remove(&record.key);
+                    stale += len;
+                    if let Some((_, old)) = kv.remove(&record.key) {
+                        stale += old.len;
+                    }
                 }
                 Command::Set => {
-                    kv.insert(record.key, pos);
+                    let pointer = LogPointer {
+                        pos,
+                        len,
+                        expires_at: record.expires_at,
+                    };
+                    let old = if pointer.expired(now) {
+                        stale += len;
+                        kv.remove(&record.key).map(|(_, old)| old)
+                    } else {
+                        kv.insert(record.key, pointer)
+                    };
+                    if let Some(old) = old {
+                        stale += old.len;
+                    }
                 }
             }
             pos += x as u64;
         }
 
+        let filter = BloomFilter::new(options.bloom_keys.max(kv.len()), options.bloom_fp_rate);
+        for entry in kv.iter() {
+            filter.insert(entry.key());
+        }
 
-        
+        let flushed = Arc::new(AtomicU64::new(writer.pos));
+        // whatever recovery read back is taken as synced
+        let synced = Arc::new(AtomicU64::new(writer.pos));
+        let log_writer = Arc::new(Mutex::new(writer));
+        // one thread serves both intervals, at the shorter of the two
+        let interval = match (options.flush_every, options.flush_interval) {
+            (Some(every), Some(sync)) => Some(every.min(sync)),
+            (every, sync) => every.or(sync),
+        };
+        let flusher = interval.map(|interval| {
+            Arc::new(Flusher::spawn(
+                Arc::clone(&log_writer),
+                Arc::clone(&flushed),
+                interval,
+                (options.flush_on_write || options.flush_interval.is_some())
+                    .then(|| Arc::clone(&synced)),
+            ))
+        });
         Ok(KvStore {
-            kv: Arc::new(kv),
-            path: Arc::new(p),
-            log_writer: Arc::new(Mutex::new(writer)),
-            reader: Arc::new(Mutex::new(BufReaderWithPos {
-                reader: reader,
-                pos: 0,
+            state: Arc::new(RwLock::new(LogState {
+                index: Arc::new(kv),
+                file: Arc::new(reader.into_inner()),
             })),
+            path: Arc::new(p),
+            log_writer,
+            flushed,
+            synced,
+            _flusher: flusher,
+            changes: Arc::new(Mutex::new(ChangeFeed::default())),
+            filter: Arc::new(RwLock::new(filter)),
+            options,
+            filtered_gets: Arc::new(AtomicU64::new(0)),
+            stale_bytes: Arc::new(AtomicU64::new(stale)),
+            limits: Limits::default(),
             // compact_daemon: Arc::new(Mutex::new(thread::spawn(move||{})))
         })
     }
 
-    fn compact(&mut self) {
-        let p: PathBuf = self.path.parent().unwrap().join("log.temp");
-        let nf = std::fs::OpenOptions::new().append(true).create(true).open(&p).unwrap();
-        let mut writer = BufWriterWithPos::new(nf).unwrap();
-        let mut reader = BufReaderWithPos::new(File::open(&p).unwrap(), 0).unwrap();
-        let mut kv = DashMap::<String, u64>::new();
-        for tuple in self.kv.iter_mut() {
-            let k = tuple.key();
-            let pos = tuple.value();
-            let mut value = String::new();
-            reader.seek(SeekFrom::Start(*pos)).unwrap();
-            reader.read_line(&mut value);
-            let record: Record = serde_json::from_str(&value).unwrap();
-            if record.cmd == Command::Set {
-                let n = writer.write(value.as_bytes()).unwrap();
-                kv.insert(k.to_owned(), writer.pos - n as u64);
-                writer.flush();
+    /// Replace the default key and value size limits checked by every write.
+    pub fn with_limits(mut self, limits: Limits) -> KvStore {
+        self.limits = limits;
+        self
+    }
+
+    /// Every live entry in key order, for backups and exports.
+    ///
+    /// Keys and their log offsets are copied when `iter` is called, and the values read
+    /// lazily from the log file of that moment. Writes and compactions that happen while
+    /// iterating aren't seen, so no key is skipped or yielded twice.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> + Send + 'static {
+        self.snapshot(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Live entries between `start` and `end` as of now, in key order, with values read
+    /// lazily from the current log file.
+    fn snapshot(
+        &self,
+        start: Bound<&String>,
+        end: Bound<&String>,
+    ) -> impl Iterator<Item = Result<(String, String)>> + Send + 'static {
+        let state = self.state();
+        let range = (start, end);
+        let now = now_millis();
+        let mut end_pos = 0;
+        let mut pointers: Vec<(String, u64)> = state
+            .index
+            .iter()
+            .filter(|entry| {
+                RangeBounds::<String>::contains(&range, entry.key()) && !entry.value().expired(now)
+            })
+            .map(|entry| {
+                let pointer = entry.value();
+                end_pos = end_pos.max(pointer.pos + pointer.len);
+                (entry.key().clone(), pointer.pos)
+            })
+            .collect();
+        pointers.sort_unstable();
+        let written_out = self.write_out_to(end_pos, None);
+        // the old file stays readable through this handle after a compaction replaces it
+        let file = state.file;
+        // failing to write the buffer out is yielded first, before reads that need it
+        written_out
+            .err()
+            .map(Err)
+            .into_iter()
+            .chain(pointers.into_iter().map(move |(key, pos)| {
+                let record = Record::decode(&read_record_at(&file, pos)?, pos)?;
+                Ok((key, record.value))
+            }))
+    }
+
+    /// Like `set`, but `get` answers `None` once `ttl` has passed.
+    ///
+    /// The expiry is an absolute wall clock time stored with the record, so it survives
+    /// reopening. Expired keys are dropped lazily by the next `get` or `remove` of the
+    /// key, when the log is replayed, or by compaction, which doesn't rewrite them.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let mut guard = self.log_writer.lock().unwrap();
+        self.append_set(&mut guard, &key, &value, Some(expires_at))?;
+        drop(guard);
+        self.record_change(key, Some(value));
+        self.maybe_compact()
+    }
+
+    /// Move every key in `[start, end)` into `dest`, returning how many keys were moved.
+    ///
+    /// Keys are moved one at a time in ascending order: each value is written to `dest`
+    /// before it is removed from `self`. If an error interrupts the drain, keys already
+    /// moved stay moved, and the key being processed may be present in both stores, but
+    /// it is never lost. Re-running the drain over the same range finishes the job.
+    pub fn drain_range_to(&self, start: String, end: String, dest: &impl KvsEngine) -> Result<u64> {
+        let mut keys: Vec<String> = self
+            .state()
+            .index
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| *key >= start && *key < end)
+            .collect();
+        keys.sort();
+
+        let mut moved = 0;
+        for key in keys {
+            // the key may have been removed concurrently since the index was read
+            if let Some(value) = self.get(key.clone())? {
+                dest.set(key.clone(), value)?;
+                self.remove(key)?;
+                moved += 1;
             }
         }
-        std::fs::rename(p, self.path.as_ref()).expect("Error");
-        self.kv = Arc::new(kv);
+        Ok(moved)
     }
-}
 
-/* impl Drop for KvStore {
-    fn drop(&mut self) {
+    /// Live key count, log size and stale bytes. The stale byte count is kept up to date
+    /// by every write, so this doesn't scan the log.
+    pub fn stats(&self) -> Stats {
+        let log_bytes = self.log_writer.lock().unwrap().pos;
+        Stats {
+            keys: self.state.read().unwrap().index.len() as u64,
+            disk_bytes: log_bytes,
+            stale_bytes: self.stale_bytes.load(Ordering::SeqCst),
+        }
+    }
 
+    /// Log offset up to which writes are known to be on disk, as made by `sync`,
+    /// `KvOptions::flush_on_write` or the `KvOptions::flush_interval` flusher. Writes
+    /// are durable once it reaches `stats().disk_bytes`.
+    pub fn synced_bytes(&self) -> u64 {
+        self.synced.load(Ordering::SeqCst)
     }
-} */
 
-#[derive(Debug)]
-struct BufReaderWithPos<R: Read + Seek> {
-    reader: BufReader<R>,
-    pos: u64,
-}
+    /// Number of `get`s answered by the bloom filter without consulting the index.
+    pub fn filtered_gets(&self) -> u64 {
+        self.filtered_gets.load(Ordering::Relaxed)
+    }
 
-impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(inner: R, pos: u64) -> Result<BufReaderWithPos<R>> {
-        let mut reader = BufReader::new(inner);
-        let pos = reader.seek(SeekFrom::Current(0))?;
-        Ok(BufReaderWithPos { reader, pos })
+    /// Cursor positioned after the most recent mutation, to be passed to `changes_since`.
+    pub fn change_cursor(&self) -> u64 {
+        self.changes.lock().unwrap().seq
+    }
+
+    /// Return the mutations made after cursor `seq`, oldest first, and the new cursor.
+    ///
+    /// The feed lives in memory and only keeps the last `CHANGE_FEED_CAPACITY` mutations
+    /// since the store was opened. A cursor that has fallen out of that window is an error
+    /// rather than a silent gap, and the caller has to resynchronize from a full read.
+    pub fn changes_since(&self, seq: u64) -> Result<(Vec<ChangeEvent>, u64)> {
+        let feed = self.changes.lock().unwrap();
+        let oldest = feed.seq - feed.events.len() as u64;
+        if seq < oldest || seq > feed.seq {
+            return Err(KvsError::CursorOutOfRange {
+                cursor: seq,
+                oldest,
+                latest: feed.seq,
+            });
+        }
+        let events = feed
+            .events
+            .iter()
+            .filter(|event| event.seq() > seq)
+            .cloned()
+            .collect();
+        Ok((events, feed.seq))
+    }
+
+    fn record_change(&self, key: String, value: Option<String>) {
+        let mut feed = self.changes.lock().unwrap();
+        feed.seq += 1;
+        let seq = feed.seq;
+        let event = match value {
+            Some(value) => ChangeEvent::Set { seq, key, value },
+            None => ChangeEvent::Remove { seq, key },
+        };
+        if feed.events.len() == CHANGE_FEED_CAPACITY {
+            feed.events.pop_front();
+        }
+        feed.events.push_back(event);
+    }
+
+    /// Rewrite the log with only the live records and swap it in.
+    ///
+    /// Writers are blocked until compaction finishes. Readers only block while the new
+    /// index and file are swapped in; reads that already took a snapshot finish against
+    /// the old file, which stays readable until its last handle is dropped.
+    ///
+    /// Returns the number of bytes the log shrank by.
+    pub fn compact(&self) -> Result<u64> {
+        let mut writer = self.log_writer.lock().unwrap();
+        writer.flush()?;
+        let old_len = writer.pos;
+        let state = self.state();
+
+        let temp_path = self.path.with_file_name("log.temp");
+        let mut compacted =
+            BufWriterWithPos::new(File::create(&temp_path)?, self.options.buffer_size)?;
+        compacted.write_all(&log_header())?;
+        let index = DashMap::<String, LogPointer>::new();
+        let now = now_millis();
+        for entry in state.index.iter() {
+            let pointer = *entry.value();
+            if pointer.expired(now) {
+                continue;
+            }
+            let pos = pointer.pos;
+            // re-encoding upgrades records written by older versions
+            let record = Record::decode(&read_record_at(&state.file, pos)?, pos)?.encode()?;
+            index.insert(
+                entry.key().clone(),
+                LogPointer {
+                    pos: compacted.pos,
+                    len: record.len() as u64,
+                    expires_at: pointer.expires_at,
+                },
+            );
+            compacted.write_all(&record)?;
+        }
+        self.replace_log(&mut writer, compacted, index)?;
+        Ok(old_len.saturating_sub(writer.pos))
+    }
+
+    /// Make `log`, written to `log.temp`, the log with `index` as its index, and keep
+    /// appending to it through `writer`. Returns the replaced state.
+    fn replace_log(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        mut log: BufWriterWithPos<File>,
+        index: DashMap<String, LogPointer>,
+    ) -> Result<LogState> {
+        log.flush()?;
+        log.writer.get_ref().sync_all()?;
+        std::fs::rename(self.path.with_file_name("log.temp"), self.path.as_ref())?;
+
+        // drop removed keys from the filter while we hold a fresh index
+        let filter = BloomFilter::new(
+            self.options.bloom_keys.max(index.len() * 2),
+            self.options.bloom_fp_rate,
+        );
+        for entry in index.iter() {
+            filter.insert(entry.key());
+        }
+        *self.filter.write().unwrap() = filter;
+        let old = std::mem::replace(
+            &mut *self.state.write().unwrap(),
+            LogState {
+                index: Arc::new(index),
+                file: Arc::new(File::open(self.path.as_ref())?),
+            },
+        );
+        // the renamed temp file is the log now, keep appending to it
+        self.flushed.store(log.pos, Ordering::SeqCst);
+        self.synced.store(log.pos, Ordering::SeqCst);
+        *writer = log;
+        self.stale_bytes.store(0, Ordering::SeqCst);
+        Ok(old)
+    }
+
+    /// Append and index a set record. The caller holds the writer lock as `writer`.
+    /// Returns the value it replaced.
+    fn append_set(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        key: &str,
+        value: &str,
+        expires_at: Option<u64>,
+    ) -> Result<Option<String>> {
+        self.limits.check(key, value.as_bytes())?;
+        let previous = self.lookup(key, Some(writer))?;
+        let record = Record {
+            cmd: Command::Set,
+            key: key.to_owned(),
+            value: value.to_owned(),
+            expires_at,
+        }
+        .encode()?;
+        writer.write_all(&record)?;
+        let pos = writer.pos - record.len() as u64;
+        self.flush_log(writer)?;
+        // into the filter first, so a concurrent get can't be filtered once the key is indexed
+        self.filter.read().unwrap().insert(key);
+        // still under the writer lock, so compaction can't swap the index in between
+        let len = record.len() as u64;
+        if let Some(old) = self.state.read().unwrap().index.insert(
+            key.to_owned(),
+            LogPointer {
+                pos,
+                len,
+                expires_at,
+            },
+        ) {
+            self.stale_bytes.fetch_add(old.len, Ordering::SeqCst);
+        }
+        debug!("Inserted: key: {key}, value: {pos}");
+        Ok(previous)
+    }
+
+    /// Append a remove record and unindex `key`, returning its value, or `None` if it
+    /// isn't present. The caller holds the writer lock as `writer`.
+    fn append_remove(
+        &self,
+        writer: &mut BufWriterWithPos<File>,
+        key: &str,
+    ) -> Result<Option<String>> {
+        // also drops the key if it expired
+        let Some(previous) = self.lookup(key, Some(writer))? else {
+            return Ok(None);
+        };
+        let index = self.state.read().unwrap().index.clone();
+        let record = Record {
+            cmd: Command::Remove,
+            key: key.to_owned(),
+            value: "".to_owned(),
+            expires_at: None,
+        }
+        .encode()?;
+        writer.write_all(&record)?;
+        self.flush_log(writer)?;
+        if let Some((_, old)) = index.remove(key) {
+            // the remove record itself is dead weight once the set before it is gone
+            self.stale_bytes
+                .fetch_add(old.len + record.len() as u64, Ordering::SeqCst);
+        }
+        Ok(Some(previous))
+    }
+
+    /// Unindex `key` if it still points at the expired `pointer`, which a concurrent
+    /// set may have replaced since. The set record stays in the log, but replay and
+    /// compaction skip it once expired.
+    fn drop_expired(&self, index: &DashMap<String, LogPointer>, key: &str, pointer: LogPointer) {
+        if index
+            .remove_if(key, |_, current| current.pos == pointer.pos)
+            .is_some()
+        {
+            self.stale_bytes.fetch_add(pointer.len, Ordering::SeqCst);
+        }
+    }
+
+    /// Hand a write to the OS, and with `flush_on_write` wait until it is on disk. With
+    /// `flush_every` the write is left to the flusher, unless it filled the buffer.
+    fn flush_log(&self, writer: &mut BufWriterWithPos<File>) -> Result<()> {
+        if self.options.flush_every.is_some() {
+            let buffered = writer.writer.buffer().len() as u64;
+            self.flushed.store(writer.pos - buffered, Ordering::SeqCst);
+        } else {
+            let synced = self.options.flush_on_write.then_some(&*self.synced);
+            write_out(writer, &self.flushed, synced)?;
+        }
+        Ok(())
+    }
+
+    /// `get`, for callers already holding the writer lock as `writer`.
+    fn lookup(
+        &self,
+        key: &str,
+        writer: Option<&mut BufWriterWithPos<File>>,
+    ) -> Result<Option<String>> {
+        if !self.filter.read().unwrap().may_contain(key) {
+            self.filtered_gets.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+        // the read lock only excludes compaction's swap, not other readers
+        let state = self.state.read().unwrap();
+        let pointer = match state.index.get(key) {
+            None => return Ok(None),
+            Some(pointer) => *pointer,
+        };
+        if pointer.expired(now_millis()) {
+            self.drop_expired(&state.index, key, pointer);
+            return Ok(None);
+        }
+        let file = Arc::clone(&state.file);
+        // compaction takes the writer lock before this one, so don't wait for it holding both
+        drop(state);
+        self.write_out_to(pointer.pos + pointer.len, writer)?;
+        let pos = pointer.pos;
+        let record = Record::decode(&read_record_at(&file, pos)?, pos)?;
+        if record.cmd == Command::Remove {
+            Ok(None)
+        } else {
+            Ok(Some(record.value))
+        }
+    }
+
+    /// Make sure the log up to offset `end` has been handed to the OS, so positioned reads
+    /// see it, taking the writer lock unless the caller holds it as `writer`.
+    ///
+    /// A log swapped out by compaction was written out in full first, so offsets into it
+    /// need nothing more.
+    fn write_out_to(&self, end: u64, writer: Option<&mut BufWriterWithPos<File>>) -> Result<()> {
+        if end <= self.flushed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match writer {
+            Some(writer) => write_out(writer, &self.flushed, None)?,
+            None => write_out(&mut self.log_writer.lock().unwrap(), &self.flushed, None)?,
+        }
+        Ok(())
+    }
+
+    /// Compact once overwritten and removed records pass the compaction threshold.
+    ///
+    /// Called after a write has released the writer lock. Concurrent writers may both
+    /// see the threshold crossed; the second compaction finds little to drop.
+    fn maybe_compact(&self) -> Result<()> {
+        if self.stale_bytes.load(Ordering::SeqCst) > self.options.compaction_threshold {
+            debug!("Stale bytes past the threshold, compacting");
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> LogState {
+        self.state.read().unwrap().clone()
     }
 }
 
-impl<R: Read + Seek> Read for BufReaderWithPos<R> {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.reader.seek(SeekFrom::Start(self.pos))?;
-        let n = self.reader.read(buf)?;
-        self.pos += n as u64;
-        Ok(n)
+/// Every clone flushes on drop, so writes still buffered in `log_writer` aren't lost when
+/// the last handle goes away without an explicit flush.
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // a writer that panicked mid-write has nothing consistent left to flush
+        let Ok(mut writer) = self.log_writer.lock() else {
+            warn!("Log writer poisoned, skipping the flush on drop");
+            return;
+        };
+        if let Err(e) = write_out(&mut writer, &self.flushed, None) {
+            warn!("Failed to flush the log on drop: {e}");
+        }
     }
 }
 
-impl<R: Read + Seek> BufRead for BufReaderWithPos<R> {
-    fn fill_buf(&mut self) -> Result<&[u8]> {
-        self.reader.fill_buf()
+/// Hand the records buffered in `writer` to the OS and advance `flushed` past them, and
+/// with `synced` wait until the log is on disk and advance `synced` too.
+fn write_out(
+    writer: &mut BufWriterWithPos<File>,
+    flushed: &AtomicU64,
+    synced: Option<&AtomicU64>,
+) -> std::io::Result<()> {
+    writer.flush()?;
+    if let Some(synced) = synced {
+        writer.writer.get_ref().sync_data()?;
+        synced.store(writer.pos, Ordering::SeqCst);
     }
+    flushed.store(writer.pos, Ordering::SeqCst);
+    Ok(())
+}
 
-    fn consume(&mut self, amt: usize) {
-        self.reader.consume(amt);
-        self.pos += amt as u64;
+/// Thread writing out the log every interval, and with `synced` also syncing it, shared by
+/// all handles of a store opened with `KvOptions::flush_every` or
+/// `KvOptions::flush_interval`.
+///
+/// Dropping it stops and joins the thread, then writes out and syncs whatever is left.
+struct Flusher {
+    writer: Arc<Mutex<BufWriterWithPos<File>>>,
+    flushed: Arc<AtomicU64>,
+    synced: Option<Arc<AtomicU64>>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn spawn(
+        writer: Arc<Mutex<BufWriterWithPos<File>>>,
+        flushed: Arc<AtomicU64>,
+        interval: Duration,
+        synced: Option<Arc<AtomicU64>>,
+    ) -> Flusher {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread_writer = Arc::clone(&writer);
+        let thread_flushed = Arc::clone(&flushed);
+        let thread_synced = synced.clone();
+        let thread = thread::spawn(move || {
+            // runs until the sender is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let Ok(mut writer) = thread_writer.lock() else {
+                    warn!("Log writer poisoned, stopping the flusher");
+                    return;
+                };
+                if let Err(e) = write_out(&mut writer, &thread_flushed, thread_synced.as_deref()) {
+                    warn!("Failed to flush the log: {e}");
+                }
+            }
+        });
+        Flusher {
+            writer,
+            flushed,
+            synced,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
     }
+}
 
-    fn read_line(&mut self, buf: &mut String) -> Result<usize> {
-        self.reader.seek(SeekFrom::Start(self.pos))?;
-        let n = self.reader.read_line(buf)?;
-        self.pos += n as u64;
-        Ok(n)
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Log flusher thread panicked");
+            }
+        }
+        let Ok(mut writer) = self.writer.lock() else {
+            return;
+        };
+        if let Err(e) = write_out(&mut writer, &self.flushed, self.synced.as_deref()) {
+            warn!("Failed to flush the log on drop: {e}");
+        }
     }
 }
 
-impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        self.pos = self.reader.seek(pos)?;
-        Ok(self.pos)
+/// Read the newline-terminated record starting at `pos`.
+///
+/// Positioned reads don't move the file cursor, so any number of threads can read
+/// through the same handle at once.
+fn read_record_at(file: &File, pos: u64) -> Result<String> {
+    let mut record = Vec::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let n = match read_at(file, &mut chunk, pos + record.len() as u64) {
+            Ok(0) => {
+                return Err(KvsError::Corrupt(format!(
+                    "No complete record at log offset {pos}"
+                )));
+            }
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(end) = chunk[..n].iter().position(|b| *b == b'\n') {
+            record.extend_from_slice(&chunk[..=end]);
+            break;
+        }
+        record.extend_from_slice(&chunk[..n]);
     }
+    String::from_utf8(record)
+        .map_err(|e| KvsError::Corrupt(format!("Record at log offset {pos}: {e}")))
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buf, offset)
 }
 
 #[derive(Debug)]
@@ -235,32 +1120,104 @@ struct BufWriterWithPos<W: Write + Seek> {
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(inner: W) -> Result<BufWriterWithPos<W>> {
-        let mut writer = BufWriter::new(inner);
-        let pos = writer.seek(SeekFrom::Current(0))?;
-        Ok(BufWriterWithPos {
-            writer: writer,
-            pos: pos,
-        })
+    /// Positions the writer at the end of `inner`, which also gives the right offsets for
+    /// files opened in append mode.
+    fn new(inner: W, capacity: usize) -> Result<BufWriterWithPos<W>> {
+        let mut writer = BufWriter::with_capacity(capacity, inner);
+        let pos = writer.seek(SeekFrom::End(0))?;
+        Ok(BufWriterWithPos { writer, pos })
     }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         // not safe for concurrency
         let n = self.writer.write(buf)?;
-        self.pos = self.writer.seek(SeekFrom::Current(0))?;
+        self.pos += n as u64;
         Ok(n)
     }
 
-    fn flush(&mut self) -> Result<()> {
+    /// Unlike the default `write_all`, also retries on `WouldBlock`.
+    fn write_all(&mut self, mut buf: &[u8]) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.writer.write(buf) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole record",
+                    ));
+                }
+                Ok(n) => {
+                    self.pos += n as u64;
+                    buf = &buf[n..];
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
         self.writer.flush()
     }
 }
 
 impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         self.pos = self.writer.seek(pos)?;
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Accepts at most three bytes per call and is interrupted every other call.
+    struct ShortWriter {
+        inner: Cursor<Vec<u8>>,
+        calls: usize,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            self.inner.write(&buf[..buf.len().min(3)])
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for ShortWriter {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn write_all_survives_short_writes() -> Result<()> {
+        let mut writer = BufWriterWithPos::new(
+            ShortWriter {
+                inner: Cursor::new(Vec::new()),
+                calls: 0,
+            },
+            DEFAULT_BUFFER_SIZE,
+        )?;
+        // larger than the BufWriter capacity, so it goes straight to the inner writer
+        let record = "x".repeat(20_000) + "\n";
+        writer.write_all(record.as_bytes())?;
+        writer.flush()?;
+
+        assert_eq!(writer.pos, record.len() as u64);
+        assert_eq!(writer.writer.get_ref().inner.get_ref(), record.as_bytes());
+        Ok(())
+    }
+}
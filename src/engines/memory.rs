@@ -0,0 +1,137 @@
+use crate::engines::{Cursor, KvsEngine, Limits, Result, Stats, WriteBatch};
+use crate::proto::Command;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// Engine keeping everything in memory, for tests and benchmarks that shouldn't touch
+/// disk. Nothing survives the last handle being dropped.
+///
+/// Single-key operations are atomic, but a write batch is applied one record at a time,
+/// so concurrent readers may see part of it.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    map: Arc<DashMap<String, String>>,
+    limits: Limits,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// Replace the default key and value size limits checked by every write.
+    pub fn with_limits(mut self, limits: Limits) -> MemoryStore {
+        self.limits = limits;
+        self
+    }
+}
+
+impl KvsEngine for MemoryStore {
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.limits.check(&key, value.as_bytes())?;
+        Ok(self.map.insert(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).map(|value| value.clone()))
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.map.contains_key(&key))
+    }
+
+    fn remove_opt(&self, key: String) -> Result<Option<String>> {
+        Ok(self.map.remove(&key).map(|(_, value)| value))
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.map.clear();
+        Ok(())
+    }
+
+    /// Nothing is durable, so there is nothing to wait for.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn approx_key_count(&self) -> Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+
+    /// Copies and sorts the entries after `after` up front.
+    fn cursor(&self, after: Option<String>) -> Result<Cursor> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.scan(start, Bound::Unbounded)?;
+        Ok(Cursor::new(entries.into_iter().map(Ok)))
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        let range = (start.as_ref(), end.as_ref());
+        let mut entries: Vec<(String, String)> = self
+            .map
+            .iter()
+            .filter(|entry| RangeBounds::<String>::contains(&range, entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    fn cas(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        if let Some(new) = &new {
+            self.limits.check(&key, new.as_bytes())?;
+        }
+        // the entry holds its shard's lock, so the compare and the swap are atomic
+        match self.map.entry(key) {
+            Entry::Occupied(mut entry) => {
+                if expected.as_deref() != Some(entry.get().as_str()) {
+                    return Ok(false);
+                }
+                match new {
+                    Some(value) => {
+                        entry.insert(value);
+                    }
+                    None => {
+                        entry.remove();
+                    }
+                }
+            }
+            Entry::Vacant(entry) => {
+                if expected.is_some() {
+                    return Ok(false);
+                }
+                if let Some(value) = new {
+                    entry.insert(value);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.limits.check_batch(&batch)?;
+        for record in batch.records() {
+            match record.cmd {
+                Command::Set => {
+                    self.map.insert(record.key.clone(), record.value.clone());
+                }
+                Command::Remove => {
+                    self.map.remove(&record.key);
+                }
+                _ => unreachable!("WriteBatch only holds sets and removes"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Nothing is on disk, so both byte counts are 0.
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.map.len() as u64,
+            disk_bytes: 0,
+            stale_bytes: 0,
+        })
+    }
+}
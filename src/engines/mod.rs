@@ -1,13 +1,321 @@
+pub mod bloom;
 pub mod kv;
+pub mod memory;
 pub mod sled;
 
-pub type Result<T> = std::result::Result<T, std::io::Error>;
+use crate::error::KvsError;
+pub use crate::error::Result;
+use crate::proto::{Command, Record};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Bound;
 
 pub use crate::engines::sled::SledStore;
 pub use kv::KvStore;
+pub use memory::MemoryStore;
 
-pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
+/// Iterator over `(key, value)` pairs in key order, returned by `KvsEngine::cursor`.
+pub struct Cursor {
+    inner: Box<dyn Iterator<Item = Result<(String, String)>> + Send>,
+}
+
+impl Cursor {
+    pub fn new(inner: impl Iterator<Item = Result<(String, String)>> + Send + 'static) -> Cursor {
+        Cursor {
+            inner: Box::new(inner),
+        }
+    }
+}
+
+impl Iterator for Cursor {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+/// Ordered sets and removes applied together by `KvsEngine::write_batch`.
+///
+/// Only `Command::Set` and `Command::Remove` records can be added, and deserializing a
+/// batch holding anything else fails. Removing a missing key is not an error.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Record>", into = "Vec<Record>")]
+pub struct WriteBatch {
+    records: Vec<Record>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.records.push(Record {
+            cmd: Command::Set,
+            key,
+            value,
+        });
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.records.push(Record {
+            cmd: Command::Remove,
+            key,
+            value: String::new(),
+        });
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl TryFrom<Vec<Record>> for WriteBatch {
+    type Error = KvsError;
+
+    fn try_from(records: Vec<Record>) -> Result<WriteBatch> {
+        match records
+            .iter()
+            .find(|record| !matches!(record.cmd, Command::Set | Command::Remove))
+        {
+            Some(record) => Err(KvsError::Unsupported(format!(
+                "{:?} in a write batch",
+                record.cmd
+            ))),
+            None => Ok(WriteBatch { records }),
+        }
+    }
+}
+
+impl From<WriteBatch> for Vec<Record> {
+    fn from(batch: WriteBatch) -> Vec<Record> {
+        batch.records
+    }
+}
+
+/// Entries per `write_batch` when importing an export.
+const IMPORT_BATCH: usize = 1000;
+
+/// One line of an export.
+#[derive(Serialize, Deserialize)]
+struct ExportEntry {
+    key: String,
+    value: String,
+}
+
+/// Write `entries` to `w` as one JSON object per line, returning how many were written.
+pub(crate) fn export_entries(
+    w: impl Write,
+    entries: impl Iterator<Item = Result<(String, String)>>,
+) -> Result<u64> {
+    let mut w = BufWriter::new(w);
+    let mut count = 0;
+    for entry in entries {
+        let (key, value) = entry?;
+        serde_json::to_writer(&mut w, &ExportEntry { key, value })?;
+        w.write_all(b"\n")?;
+        count += 1;
+    }
+    w.flush()?;
+    Ok(count)
+}
+
+/// Read an export from `r` and hand its entries to `apply` as batches of sets, returning
+/// how many entries were read. Blank lines are skipped.
+pub(crate) fn import_entries(
+    r: impl Read,
+    mut apply: impl FnMut(WriteBatch) -> Result<()>,
+) -> Result<u64> {
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for (line_num, line) in BufReader::new(r).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ExportEntry = serde_json::from_str(&line)
+            .map_err(|e| KvsError::Corrupt(format!("Line {} of the export: {e}", line_num + 1)))?;
+        batch.set(entry.key, entry.value);
+        count += 1;
+        if batch.len() == IMPORT_BATCH {
+            apply(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        apply(batch)?;
+    }
+    Ok(count)
+}
+
+/// Largest key engines accept by default, in bytes.
+pub const DEFAULT_MAX_KEY_SIZE: usize = 64 * 1024;
+/// Largest value engines accept by default, in bytes.
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Largest key and value an engine accepts, in bytes.
+///
+/// Writes are checked before anything reaches the engine, so an oversized set, cas or
+/// batch fails as a whole and leaves the data untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+        }
+    }
+}
+
+impl Limits {
+    /// Fail with `KvsError::KeyTooLarge` or `KvsError::ValueTooLarge` if setting `key` to
+    /// `value` would exceed the limits.
+    pub fn check(&self, key: &str, value: &[u8]) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(KvsError::KeyTooLarge {
+                size: key.len(),
+                max: self.max_key_size,
+            });
+        }
+        if value.len() > self.max_value_size {
+            return Err(KvsError::ValueTooLarge {
+                size: value.len(),
+                max: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// `check` every set of `batch`. Removes always pass, an oversized key can't exist.
+    pub fn check_batch(&self, batch: &WriteBatch) -> Result<()> {
+        batch
+            .records()
+            .iter()
+            .filter(|record| record.cmd == Command::Set)
+            .try_for_each(|record| self.check(&record.key, record.value.as_bytes()))
+    }
+}
+
+/// Key count and disk usage of an engine, returned by `KvsEngine::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Number of live keys, as counted by `KvsEngine::approx_key_count`.
+    pub keys: u64,
+    /// Bytes the engine's data takes on disk.
+    pub disk_bytes: u64,
+    /// Bytes on disk that no longer back a live key, which compaction would reclaim.
+    pub stale_bytes: u64,
+}
+
+/// A key-value storage engine.
+///
+/// Engines are cheap handles onto shared state: the server clones one per connection and
+/// moves it to a worker thread, and clones may also be shared by reference across threads.
+/// Every engine must therefore be `Send + Sync`, with all methods safe to call concurrently.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Set `key` to `value`, returning the value it replaced.
+    fn set(&self, key: String, value: String) -> Result<Option<String>>;
     fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// Remove `key`, returning the removed value, or `None` if it wasn't present.
+    ///
+    /// A missing key is not an error; `Err` means the engine failed.
+    fn remove_opt(&self, key: String) -> Result<Option<String>>;
+
+    /// Number of live keys, possibly approximate.
+    ///
+    /// `SledStore` keeps an exact count. `KvStore`'s is exact too, except that it includes
+    /// expired keys until they are dropped. An engine that would need a full merge to count
+    /// live keys may return an estimate instead, and must document its error bound.
+    fn approx_key_count(&self) -> Result<u64>;
+
+    /// Iterate over entries in key order, starting just after `after` (or at the first key).
+    ///
+    /// Resuming from the last key of one page with a fresh cursor never repeats or skips
+    /// keys that existed throughout, whatever is written meanwhile.
+    fn cursor(&self, after: Option<String>) -> Result<Cursor>;
+
+    /// Entries with keys between `start` and `end`, in key order.
+    ///
+    /// An empty or inverted range gives no entries rather than an error.
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>>;
+
+    /// Set `key` to `new`, or remove it if `new` is `None`, but only if its current value
+    /// is `expected` (`None` meaning absent). Returns whether the swap happened.
+    fn cas(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool>;
+
+    /// Apply every operation of `batch` in order, with a single flush at the end.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Remove every key, freeing the disk space they used.
+    fn clear(&self) -> Result<()>;
+
+    /// Make every write that has returned durable, for callers that write without a
+    /// durable flush each time and group their flushes instead.
+    fn sync(&self) -> Result<()>;
+
+    /// Key count and disk usage of the engine.
+    fn stats(&self) -> Result<Stats>;
+
+    /// Set `key` to a raw byte value.
+    ///
+    /// Engines that only store strings accept UTF-8 values and fail with
+    /// `KvsError::Unsupported` for anything else.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        match String::from_utf8(value) {
+            Ok(value) => self.set(key, value).map(|_| ()),
+            Err(_) => Err(KvsError::Unsupported(format!(
+                "Value of key {key} isn't UTF-8, which this engine can't store"
+            ))),
+        }
+    }
+
+    /// Get the value of `key` as raw bytes.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key)?.map(String::into_bytes))
+    }
+
+    /// Whether `key` has a value, without reading the value if the engine can help it.
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Values of `keys`, in the same order, with `None` for missing keys.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Write every live entry to `w` in key order, one JSON object per line like
+    /// `{"key":"key1","value":"value1"}`. Returns the number of entries written.
+    ///
+    /// Only current values are exported, so the output loads into any engine.
+    fn export(&self, w: impl Write) -> Result<u64> {
+        export_entries(w, self.cursor(None)?)
+    }
+
+    /// Set every entry of an export read from `r`, a batch of entries at a time. Keys
+    /// missing from the export are left alone. Returns the number of entries read.
+    ///
+    /// A failure partway leaves the batches before it applied.
+    fn import(&self, r: impl Read) -> Result<u64> {
+        import_entries(r, |batch| self.write_batch(batch))
+    }
+
+    /// Remove `key` and return its value, failing with `KvsError::KeyNotFound` if it
+    /// doesn't exist.
+    fn remove(&self, key: String) -> Result<String> {
+        self.remove_opt(key)?.ok_or(KvsError::KeyNotFound)
+    }
 }
@@ -1,41 +1,395 @@
-use crate::engines::{KvsEngine, Result};
-use log::debug;
-use sled::Db;
-use std::io::ErrorKind;
+use crate::engines::{Cursor, KvsEngine, Limits, Result, Stats, WriteBatch};
+use crate::error::KvsError;
+use crate::proto::Command;
+use log::warn;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use sled::{Db, IVec};
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+/// How many times `open` retries while the database's file lock is still held.
+const LOCK_RETRIES: u32 = 50;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Result of an operation inside a [`SledStore::transaction`] closure.
+///
+/// Propagate errors from [`SledTransaction`] methods with `?` so that conflicts reach
+/// sled and the closure gets retried. Return `Err(ConflictableTransactionError::Abort(e))`
+/// to abort the transaction with `e`.
+pub type TransactionResult<T> = std::result::Result<T, ConflictableTransactionError<KvsError>>;
+
+/// Engine backed by a sled database.
+///
+/// `sled::Db` is itself a thread-safe handle, so clones share the database and every
+/// operation is atomic with respect to concurrent callers.
+///
+/// By default every write is flushed before it returns. A store opened with
+/// [`SledStore::open_with_options`] and a `flush_every` interval leaves flushing to a
+/// background thread instead, which flushes once more when the last handle is dropped.
 #[derive(Clone)]
 pub struct SledStore {
     db: Db,
+    flusher: Option<Arc<Flusher>>,
+    limits: Limits,
 }
 
 impl KvsEngine for SledStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.as_bytes().to_vec())?;
-        self.db.flush()?;
-        Ok(())
+    /// Fails with `KvsError::Corrupt` if the replaced value isn't UTF-8, in which case
+    /// `value` has still been written.
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.insert(&key, value.as_bytes())?;
+        old.map(|old| decode(&key, &old)).transpose()
     }
     fn get(&self, key: String) -> Result<Option<String>> {
         match self.db.get(&key)? {
             None => Ok(None),
-            Some(v) => Ok(Some(std::str::from_utf8(v.as_ref()).unwrap().to_string())),
+            Some(v) => Ok(Some(decode(&key, &v)?)),
         }
     }
-    fn remove(&self, key: String) -> Result<()> {
-        match self.db.get(&key)? {
-            None => Err(std::io::Error::new(ErrorKind::Other, "Non existent key")),
-            Some(_) => {
-                self.db.remove(key)?;
-                self.db.flush()?;
-                Ok(())
+
+    fn cas(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        if let Some(new) = &new {
+            self.limits.check(&key, new.as_bytes())?;
+        }
+        match self.db.compare_and_swap(
+            key,
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )? {
+            Ok(()) => {
+                self.flush_write()?;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Applied as one `sled::Batch`, so the batch is atomic.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.limits.check_batch(&batch)?;
+        let mut sled_batch = sled::Batch::default();
+        for record in batch.records() {
+            match record.cmd {
+                Command::Set => sled_batch.insert(record.key.as_bytes(), record.value.as_bytes()),
+                Command::Remove => sled_batch.remove(record.key.as_bytes()),
+                _ => unreachable!("WriteBatch only holds sets and removes"),
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        self.flush_write()?;
+        Ok(())
+    }
+
+    /// Sled frees the space of the cleared keys as it rewrites its segments, not at once.
+    fn clear(&self) -> Result<()> {
+        self.db.clear()?;
+        self.flush_write()
+    }
+
+    /// Flushes whatever a store opened with a `flush_every` interval hasn't flushed yet.
+    fn sync(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// sled doesn't report how much of its space is reclaimable, so `stale_bytes` is 0.
+    fn stats(&self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            stale_bytes: 0,
+        })
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.insert(&key, &value)?;
+        Ok(())
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    /// Reads the tree directly, without a `String` round trip per key through `get`.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in &keys {
+            values.push(self.db.get(key)?.map(|v| decode(key, &v)).transpose()?);
+        }
+        Ok(values)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|v| v.to_vec()))
+    }
+
+    /// `Db::len` walks the whole tree, so this is exact but linear in the number of keys.
+    fn approx_key_count(&self) -> Result<u64> {
+        Ok(self.db.len() as u64)
+    }
+
+    fn cursor(&self, after: Option<String>) -> Result<Cursor> {
+        let iter = match after {
+            None => self.db.iter(),
+            Some(after) => self
+                .db
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+        };
+        Ok(Cursor::new(iter.map(decode_entry)))
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        let range = (
+            start.as_ref().map(String::as_bytes),
+            end.as_ref().map(String::as_bytes),
+        );
+        self.db.range::<&[u8], _>(range).map(decode_entry).collect()
+    }
+
+    fn remove_opt(&self, key: String) -> Result<Option<String>> {
+        match self.db.remove(&key)? {
+            None => Ok(None),
+            Some(old) => {
+                self.flush_write()?;
+                Ok(Some(decode(&key, &old)?))
             }
         }
     }
 }
 
 impl SledStore {
+    /// Insert `value` and flush, returning the value it replaced.
+    fn insert(&self, key: &str, value: &[u8]) -> Result<Option<IVec>> {
+        self.limits.check(key, value)?;
+        let old = self.db.insert(key, value)?;
+        // sled doesn't log a set that leaves the value as it was, and a flush with nothing
+        // new to write returns at once, so unchanged sets stay cheap without special casing
+        self.flush_write()?;
+        Ok(old)
+    }
+
+    /// Flush a write before returning, unless flushing is left to the background flusher.
+    fn flush_write(&self) -> Result<()> {
+        if self.flusher.is_none() {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Open the database in `path`, creating it if needed.
+    ///
+    /// A database written by an incompatible version of sled fails with
+    /// `KvsError::IncompatibleFormat`. If the database is still locked by another handle,
+    /// opening waits up to half a second for it to be released.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledStore> {
-        let db = sled::open(path.into())?;
-        Ok(SledStore { db })
+        SledStore::open_with_options(path, None)
+    }
+
+    /// Open the database in `path`, flushing every `flush_every` instead of on every write.
+    ///
+    /// With `Some(interval)`, writes return before they are durable: a crash loses up to
+    /// `interval` worth of acknowledged writes, in exchange for much cheaper writes. A
+    /// background thread flushes on that interval and once more when the last handle is
+    /// dropped. `None` flushes every write before it returns, like [`SledStore::open`].
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        flush_every: Option<Duration>,
+    ) -> Result<SledStore> {
+        // we flush ourselves, so sled's own flusher stays off
+        let config = sled::Config::new().path(path.into()).flush_every_ms(None);
+        let mut retries = 0;
+        let opened = loop {
+            match config.open() {
+                // sled's IO threads release the file lock shortly after the last handle of
+                // a previous open is dropped
+                Err(e) if lock_contended(&e) && retries < LOCK_RETRIES => {
+                    retries += 1;
+                    thread::sleep(LOCK_RETRY_DELAY);
+                }
+                result => break result,
+            }
+        };
+        // sled reports on-disk format and version mismatches as `Unsupported`
+        let db = opened.map_err(|e| match e {
+            sled::Error::Unsupported(msg) => KvsError::IncompatibleFormat(msg),
+            e => e.into(),
+        })?;
+        Ok(SledStore {
+            limits: Limits::default(),
+            flusher: flush_every.map(|interval| Arc::new(Flusher::spawn(db.clone(), interval))),
+            db,
+        })
+    }
+
+    /// Replace the default key and value size limits checked by every write, except those
+    /// made inside a `transaction`.
+    pub fn with_limits(mut self, limits: Limits) -> SledStore {
+        self.limits = limits;
+        self
+    }
+
+    /// Run `f` as a serializable transaction over the whole store.
+    ///
+    /// sled retries `f` on conflict, so it may run several times and must not have side
+    /// effects outside of the transaction handle. Writes become visible atomically and are
+    /// flushed like any other write once `f` succeeds. An aborted transaction writes nothing.
+    pub fn transaction<F, A>(&self, f: F) -> Result<A>
+    where
+        F: Fn(&SledTransaction) -> TransactionResult<A>,
+    {
+        let result = self
+            .db
+            .transaction(|tree| f(&SledTransaction { tree }))
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        self.flush_write()?;
+        Ok(result)
+    }
+}
+
+/// Thread flushing the database every interval, shared by all handles of a store.
+///
+/// Dropping it stops and joins the thread, then flushes whatever was written since.
+struct Flusher {
+    db: Db,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn spawn(db: Db, interval: Duration) -> Flusher {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread_db = db.clone();
+        let thread = thread::spawn(move || {
+            // runs until the sender is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = thread_db.flush() {
+                    warn!("Failed to flush sled: {e}");
+                }
+            }
+        });
+        Flusher {
+            db,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Sled flusher thread panicked");
+            }
+        }
+        if let Err(e) = self.db.flush() {
+            warn!("Failed to flush sled on drop: {e}");
+        }
+    }
+}
+
+/// Handle to the store inside a [`SledStore::transaction`] closure.
+pub struct SledTransaction<'a> {
+    tree: &'a TransactionalTree,
+}
+
+impl SledTransaction<'_> {
+    pub fn get(&self, key: &str) -> TransactionResult<Option<String>> {
+        match self.tree.get(key)? {
+            None => Ok(None),
+            Some(v) => match decode(key, &v) {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => Err(ConflictableTransactionError::Abort(e)),
+            },
+        }
+    }
+
+    pub fn insert(&self, key: &str, value: String) -> TransactionResult<()> {
+        self.tree.insert(key, value.into_bytes())?;
+        Ok(())
+    }
+
+    /// Returns whether the key existed.
+    pub fn remove(&self, key: &str) -> TransactionResult<bool> {
+        Ok(self.tree.remove(key)?.is_some())
+    }
+}
+
+/// Whether opening failed because another handle still holds the database's file lock.
+fn lock_contended(e: &sled::Error) -> bool {
+    matches!(e, sled::Error::Io(e) if e.to_string().starts_with("could not acquire lock"))
+}
+
+fn decode(key: &str, value: &[u8]) -> Result<String> {
+    String::from_utf8(value.to_vec())
+        .map_err(|e| KvsError::Corrupt(format!("Value of key {key}: {e}")))
+}
+
+fn decode_entry(entry: sled::Result<(IVec, IVec)>) -> Result<(String, String)> {
+    let (key, value) = entry?;
+    let key = String::from_utf8(key.to_vec())
+        .map_err(|e| KvsError::Corrupt(format!("Key in sled: {e}")))?;
+    let value = decode(&key, &value)?;
+    Ok((key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn count_events(subscriber: &mut sled::Subscriber) -> usize {
+        let mut n = 0;
+        while subscriber.next_timeout(Duration::from_millis(50)).is_ok() {
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn remove_is_a_single_write() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = SledStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut subscriber = store.db.watch_prefix(vec![]);
+        assert_eq!(
+            store.remove_opt("key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(count_events(&mut subscriber), 1);
+        Ok(())
+    }
+
+    // sled itself drops a set that leaves the value as it was
+    #[test]
+    fn unchanged_set() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = SledStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut subscriber = store.db.watch_prefix(vec![]);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(count_events(&mut subscriber), 0);
+
+        let mut subscriber = store.db.watch_prefix(vec![]);
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(count_events(&mut subscriber), 1);
+
+        // Open from disk again and check the changed value was flushed
+        drop(subscriber);
+        drop(store);
+        let store = SledStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        Ok(())
     }
 }
@@ -0,0 +1,149 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Error type for all kvs operations.
+#[derive(Debug)]
+pub enum KvsError {
+    Io(io::Error),
+    Serde(serde_json::Error),
+    Sled(sled::Error),
+    /// Removing a key that doesn't exist.
+    KeyNotFound,
+    /// The data directory was written by a different engine than the one requested.
+    EngineMismatch {
+        expected: String,
+        found: String,
+    },
+    /// The data directory was written in an on-disk format this build can't open.
+    IncompatibleFormat(String),
+    /// An operation the server reported as failed, with the server's message.
+    Server(String),
+    /// A response that doesn't answer the request it was read for.
+    UnexpectedResponse,
+    /// Persisted data that can't be decoded.
+    Corrupt(String),
+    /// An operation the engine can't perform, with the reason.
+    Unsupported(String),
+    /// A request whose arguments can't be decoded.
+    InvalidRequest(String),
+    /// An address that isn't a valid `IP:PORT` socket address.
+    InvalidAddress(String),
+    /// A thread pool that couldn't be created.
+    ThreadPool(String),
+    /// A key longer than the engine's `Limits::max_key_size`, in bytes.
+    KeyTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A value longer than the engine's `Limits::max_value_size`, in bytes.
+    ValueTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// A frame header announcing more than the `max` bytes the reader accepts.
+    FrameTooLarge {
+        len: u32,
+        max: u32,
+    },
+    /// A connection refused because the server already serves `max` connections.
+    ServerBusy {
+        max: usize,
+    },
+    /// A directory that already holds data where a fresh one was expected.
+    DataExists(PathBuf),
+    /// A directory whose import was interrupted, holding only part of the source data.
+    ImportInterrupted(PathBuf),
+    /// A change feed cursor outside of the retained `oldest..=latest` window.
+    CursorOutOfRange {
+        cursor: u64,
+        oldest: u64,
+        latest: u64,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, KvsError>;
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::Io(e) => write!(f, "IO error: {e}"),
+            KvsError::Serde(e) => write!(f, "Serialization error: {e}"),
+            KvsError::Sled(e) => write!(f, "Sled error: {e}"),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::EngineMismatch { expected, found } => {
+                write!(f, "Wrong engine: expected {expected}, found {found}")
+            }
+            KvsError::IncompatibleFormat(msg) => write!(
+                f,
+                "Incompatible on-disk format: {msg}. Export the data with the version of kvs \
+                 that wrote it, or start over with an empty directory"
+            ),
+            KvsError::Server(msg) => write!(f, "Server error: {msg}"),
+            KvsError::UnexpectedResponse => write!(f, "Unexpected response from the server"),
+            KvsError::Corrupt(msg) => write!(f, "Corrupt data: {msg}"),
+            KvsError::Unsupported(msg) => write!(f, "Unsupported operation: {msg}"),
+            KvsError::InvalidRequest(msg) => write!(f, "Invalid request: {msg}"),
+            KvsError::ThreadPool(msg) => write!(f, "Thread pool error: {msg}"),
+            KvsError::InvalidAddress(addr) => write!(
+                f,
+                "Invalid address {addr:?}: expected IP:PORT, like 127.0.0.1:4000 or [::1]:4000"
+            ),
+            KvsError::KeyTooLarge { size, max } => {
+                write!(f, "Key of {size} bytes is over the limit of {max} bytes")
+            }
+            KvsError::ValueTooLarge { size, max } => {
+                write!(f, "Value of {size} bytes is over the limit of {max} bytes")
+            }
+            KvsError::FrameTooLarge { len, max } => {
+                write!(f, "Frame of {len} bytes is over the limit of {max} bytes")
+            }
+            KvsError::ServerBusy { max } => {
+                write!(f, "Server busy: already serving {max} connections")
+            }
+            KvsError::DataExists(dir) => write!(f, "{} already holds data", dir.display()),
+            KvsError::ImportInterrupted(dir) => write!(
+                f,
+                "The import into {} was interrupted, start again with --import-from to finish it",
+                dir.display()
+            ),
+            KvsError::CursorOutOfRange {
+                cursor,
+                oldest,
+                latest,
+            } => write!(
+                f,
+                "Change cursor {cursor} outside of the retained range {oldest}..={latest}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Serde(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KvsError {
+    fn from(e: io::Error) -> KvsError {
+        KvsError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> KvsError {
+        KvsError::Serde(e)
+    }
+}
+
+impl From<sled::Error> for KvsError {
+    fn from(e: sled::Error) -> KvsError {
+        KvsError::Sled(e)
+    }
+}
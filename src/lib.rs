@@ -1,14 +1,20 @@
 //#![feature(test)]
 #![allow(soft_unstable)]
 
+pub mod cache;
+pub mod client;
 pub mod engines;
+pub mod error;
 pub mod proto;
+pub mod server;
 pub mod thread_pool;
 
+pub use client::KvsClient;
 pub use engines::kv::KvStore;
+pub use engines::memory::MemoryStore;
 pub use engines::sled::SledStore;
-pub use engines::KvsEngine;
-pub use engines::Result;
+pub use engines::{KvsEngine, Limits, Stats, WriteBatch};
+pub use error::{KvsError, Result};
 pub use proto::Command;
 pub use proto::Record;
 pub use thread_pool::naive::NaiveThreadPool;
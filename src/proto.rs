@@ -1,15 +1,339 @@
+use crate::{KvsError, Limits, Result, Stats, WriteBatch};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+/// A request to the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Up to `limit` entries after `after` in key order, from the first key if `after` is
+    /// `None`.
+    Scan {
+        after: Option<String>,
+        limit: Option<usize>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    /// Set `key` to `new`, or remove it if `new` is `None`, if its value is `expected`.
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    Stats,
+    GetMany {
+        keys: Vec<String>,
+    },
+    /// Whether `key` has a value.
+    Exists {
+        key: String,
+    },
+    /// Remove every key.
+    Clear,
+}
+
+impl Request {
+    /// Name of the operation, for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Get { .. } => "get",
+            Request::Set { .. } => "set",
+            Request::Remove { .. } => "rm",
+            Request::Scan { .. } => "scan",
+            Request::Batch { .. } => "batch",
+            Request::Cas { .. } => "cas",
+            Request::Stats => "stats",
+            Request::GetMany { .. } => "mget",
+            Request::Exists { .. } => "exists",
+            Request::Clear => "clear",
+        }
+    }
+
+    /// The key of a single-key request.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::Remove { key }
+            | Request::Cas { key, .. }
+            | Request::Exists { key } => Some(key),
+            Request::Scan { .. }
+            | Request::Batch { .. }
+            | Request::Stats
+            | Request::GetMany { .. }
+            | Request::Clear => None,
+        }
+    }
+
+    /// Whether the request may change the store.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::Batch { .. }
+            | Request::Cas { .. }
+            | Request::Clear => true,
+            Request::Get { .. }
+            | Request::Scan { .. }
+            | Request::Stats
+            | Request::GetMany { .. }
+            | Request::Exists { .. } => false,
+        }
+    }
+}
+
+/// A request frame as sent by a current client, or by an older one as a `Record`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum IncomingRequest {
+    Request(Request),
+    Legacy(Record),
+}
+
+impl TryFrom<IncomingRequest> for Request {
+    type Error = KvsError;
+
+    fn try_from(incoming: IncomingRequest) -> Result<Request> {
+        match incoming {
+            IncomingRequest::Request(request) => Ok(request),
+            IncomingRequest::Legacy(record) => record.try_into(),
+        }
+    }
+}
+
+/// Decodes the arguments older clients packed into `key` and `value`.
+impl TryFrom<Record> for Request {
+    type Error = KvsError;
+
+    fn try_from(record: Record) -> Result<Request> {
+        let Record { cmd, key, value } = record;
+        let invalid = |what: &str, e: &dyn std::fmt::Display| {
+            KvsError::InvalidRequest(format!("{what}: {e}"))
+        };
+        Ok(match cmd {
+            Command::Get => Request::Get { key },
+            Command::Set => Request::Set { key, value },
+            Command::Remove => Request::Remove { key },
+            Command::Scan => Request::Scan {
+                after: Some(key).filter(|key| !key.is_empty()),
+                limit: match value.as_str() {
+                    "" => None,
+                    limit => Some(limit.parse().map_err(|e| invalid("scan limit", &e))?),
+                },
+            },
+            Command::Batch => Request::Batch {
+                batch: serde_json::from_str(&value).map_err(|e| invalid("write batch", &e))?,
+            },
+            Command::Cas => {
+                let (expected, new) =
+                    serde_json::from_str(&value).map_err(|e| invalid("compare-and-swap", &e))?;
+                Request::Cas { key, expected, new }
+            }
+            Command::Stats => Request::Stats,
+            Command::GetMany => Request::GetMany {
+                keys: serde_json::from_str(&value).map_err(|e| invalid("key list", &e))?,
+            },
+        })
+    }
+}
+
+/// Operation of a `Record`, the request format of older clients, and of the entries of a
+/// `WriteBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Command {
     Get,
     Set,
     Remove,
+    /// Entries after `key` in key order, from the first key if `key` is empty. `value`
+    /// is the maximum number of entries, or empty for no limit.
+    Scan,
+    /// Apply a `WriteBatch`, serialized as JSON in `value`. `key` is unused.
+    Batch,
+    /// Compare-and-swap `key`. `value` is the JSON array `[expected, new]` of two
+    /// optional strings, `null` meaning absent.
+    Cas,
+    /// The engine's `Stats`. `key` and `value` are unused.
+    Stats,
+    /// Get several keys at once. `value` is the JSON array of keys, and `key` is unused.
+    GetMany,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A request as older clients send it, with every argument packed into `key` and
+/// `value`. Servers still accept it; see `IncomingRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Record {
     pub cmd: Command,
     pub key: String,
     pub value: String,
 }
+
+/// The server's answer to a `Request`.
+///
+/// `Ok` carries the value for a get (`None` when the key is missing), the value a set
+/// replaced, the value a remove removed, and nothing for a batch or a clear. `KeyNotFound` answers
+/// a remove of a missing key, and `Entries` a scan.
+/// `Swapped` tells whether a compare-and-swap happened, `Stats` answers a stats request,
+/// `Values` a multi-get, with one `KeyResult` per key in the order of its keys, and `Exists` an exists request. `Err`
+/// carries the error message of a failed operation.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum Response {
+    Ok(Option<String>),
+    KeyNotFound,
+    Entries(Vec<(String, String)>),
+    Swapped(bool),
+    Stats(Stats),
+    Values(Vec<KeyResult>),
+    Exists(bool),
+    Err(String),
+}
+
+/// Outcome of one key of a multi-get, so that a key failing to read doesn't fail the
+/// others.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum KeyResult {
+    Found(String),
+    NotFound,
+    /// The error message of the failed read.
+    Error(String),
+}
+
+impl From<Result<Option<String>>> for KeyResult {
+    fn from(value: Result<Option<String>>) -> KeyResult {
+        match value {
+            Ok(Some(value)) => KeyResult::Found(value),
+            Ok(None) => KeyResult::NotFound,
+            Err(e) => KeyResult::Error(e.to_string()),
+        }
+    }
+}
+
+/// Parse an `IP:PORT` address, with IPv6 addresses in brackets like `[::1]:4000`.
+pub fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    addr.parse()
+        .map_err(|_| KvsError::InvalidAddress(addr.to_owned()))
+}
+
+/// Write `msg` as a frame: a 4-byte big-endian length, which counts the header itself,
+/// followed by `msg` as JSON. Requests and responses are framed the same way.
+pub fn write_frame(writer: &mut impl Write, msg: &impl Serialize) -> Result<()> {
+    writer.write_all(&encode_frame(msg)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// `msg` framed as by `write_frame`, header included.
+pub(crate) fn encode_frame(msg: &impl Serialize) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(msg)?;
+    let mut frame = Vec::with_capacity(body.len() + 4);
+    frame.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Read one frame written by `write_frame`.
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> Result<T> {
+    match try_read_frame(reader)? {
+        Some(msg) => Ok(msg),
+        None => Err(io::Error::new(ErrorKind::UnexpectedEof, "Connection closed").into()),
+    }
+}
+
+/// Read one frame, or `None` if the peer closed the connection or sent a zero length
+/// header to end the session.
+pub fn try_read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    try_read_frame_within(reader, MAX_FRAME_LEN)
+}
+
+/// Like `try_read_frame`, but refuses frames over `max_len` bytes instead of
+/// `MAX_FRAME_LEN`.
+pub fn try_read_frame_within<T: DeserializeOwned>(
+    reader: &mut impl Read,
+    max_len: u32,
+) -> Result<Option<T>> {
+    // big end in network programming
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(
+                    io::Error::new(ErrorKind::UnexpectedEof, "Truncated frame header").into(),
+                )
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let Some(body_len) = body_len(header, max_len)? else {
+        return Ok(None);
+    };
+    // grow with the bytes that actually arrive rather than trusting the header
+    let mut body = Vec::with_capacity(body_len.min(INITIAL_BODY_CAPACITY));
+    reader.take(body_len as u64).read_to_end(&mut body)?;
+    if body.len() < body_len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated frame body").into());
+    }
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// Largest frame, header included, that `read_frame` accepts, and the most a server
+/// accepts whatever its limits.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Bytes reserved for a frame body before any of it is read. The body is read in as it
+/// arrives, so a bogus header can't make the reader allocate what it announces.
+pub(crate) const INITIAL_BODY_CAPACITY: usize = 64 * 1024;
+
+/// Bytes a request may spend on everything but its keys and values: the header, the
+/// JSON structure and field names.
+const REQUEST_OVERHEAD: usize = 4 * 1024;
+
+/// Largest request frame, header included, a server with `limits` accepts.
+///
+/// It fits the largest write, a cas with a key and two values of the largest sizes, as
+/// long as its strings need little escaping, and never exceeds `MAX_FRAME_LEN`, so no
+/// request is larger than the responses clients accept. A batch or a multi-get has to
+/// fit in it as a whole.
+pub fn max_request_len(limits: Limits) -> u32 {
+    limits
+        .max_key_size
+        .saturating_add(limits.max_value_size.saturating_mul(2))
+        .saturating_add(REQUEST_OVERHEAD)
+        .try_into()
+        .map_or(MAX_FRAME_LEN, |len: u32| len.min(MAX_FRAME_LEN))
+}
+
+/// Length of the body following a frame `header`, or `None` for a zero length header.
+/// Frames over `max_len` bytes are refused before their body is read.
+pub(crate) fn body_len(header: [u8; 4], max_len: u32) -> Result<Option<usize>> {
+    let length = u32::from_be_bytes(header);
+    if length == 0 {
+        return Ok(None);
+    }
+    if length < 4 {
+        return Err(KvsError::Corrupt(format!(
+            "Frame length {length} is too short"
+        )));
+    }
+    if length > max_len {
+        return Err(KvsError::FrameTooLarge {
+            len: length,
+            max: max_len,
+        });
+    }
+    Ok(Some(length as usize - 4))
+}
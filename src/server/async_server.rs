@@ -0,0 +1,257 @@
+use super::{ConnectionSlot, ServerConfig};
+use crate::proto::{
+    body_len, encode_frame, max_request_len, IncomingRequest, Request, Response,
+    INITIAL_BODY_CAPACITY,
+};
+use crate::{KvsEngine, KvsError, Result};
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Engine whose operations can be awaited, for [`AsyncKvServer`].
+///
+/// Blocking engines are adapted with [`SpawnBlocking`].
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+    /// Set `key` to `value`, returning the value it replaced.
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> impl Future<Output = Result<Option<String>>> + Send;
+    /// Remove `key`, returning its value, or `None` if it wasn't there.
+    fn remove(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+}
+
+/// Runs each operation of a blocking engine on tokio's blocking thread pool.
+#[derive(Clone)]
+pub struct SpawnBlocking<E>(pub E);
+
+impl<E: KvsEngine> SpawnBlocking<E> {
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine = self.0.clone();
+        match tokio::task::spawn_blocking(move || f(engine)).await {
+            Ok(result) => result,
+            // the engine call panicked
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine for SpawnBlocking<E> {
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.get(key)).await
+    }
+
+    async fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.set(key, value)).await
+    }
+
+    async fn remove(&self, key: String) -> Result<Option<String>> {
+        self.run(move |engine| engine.remove_opt(key)).await
+    }
+}
+
+/// Connections served at once without `ServerConfig::max_connections`. A task holds no
+/// worker, so the limit doesn't follow the worker count as it does for `KvServer`.
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Server that handles each connection as a tokio task instead of on a thread pool.
+///
+/// Speaks the same protocol as [`KvServer`](super::KvServer), keep-alive included, but
+/// only serves get, set and remove; other commands are answered with an error. The
+/// read cache and group commit settings of the config are ignored.
+pub struct AsyncKvServer {
+    config: ServerConfig,
+    connections: Arc<AtomicUsize>,
+}
+
+impl AsyncKvServer {
+    pub fn new(config: ServerConfig) -> AsyncKvServer {
+        AsyncKvServer {
+            config,
+            connections: Arc::default(),
+        }
+    }
+
+    /// Bind the configured address and serve connections until the listener fails.
+    pub async fn start(&self, store: impl AsyncKvsEngine) -> Result<()> {
+        let engine = &self.config.engine;
+        let ip = &self.config.addr;
+        info!(env!("CARGO_PKG_VERSION"));
+        info!("ENGINE: {engine}, IP: {ip}");
+
+        self.run(TcpListener::bind(ip).await?, store).await
+    }
+
+    /// Serve connections accepted by `listener`, each in its own task with its own clone
+    /// of `store`.
+    pub async fn run(&self, listener: TcpListener, store: impl AsyncKvsEngine) -> Result<()> {
+        info!("Listen at {}", listener.local_addr()?);
+        // no request within the engine's limits is larger
+        let max_len = max_request_len(self.config.limits());
+        let idle_timeout = self.config.idle_timeout();
+
+        loop {
+            match listener.accept().await {
+                Ok((socket, addr)) => {
+                    let max = self
+                        .config
+                        .max_connections
+                        .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+                    let Some(slot) = ConnectionSlot::reserve(&self.connections, max) else {
+                        let busy = KvsError::ServerBusy { max };
+                        warn!("Refusing client {addr}: {busy}");
+                        tokio::spawn(Self::reject(socket, busy));
+                        continue;
+                    };
+                    info!("New client: {addr}");
+                    let store = store.clone();
+                    tokio::spawn(async move {
+                        let _slot = slot;
+                        Self::serve(socket, store, max_len, idle_timeout).await
+                    });
+                }
+                Err(e) => error!("Failed to accept a connection: {e}"),
+            }
+        }
+    }
+
+    /// Send `busy` to a client past `max_connections`, then hang up.
+    async fn reject(mut socket: TcpStream, busy: KvsError) {
+        let sent = match encode_frame(&Response::Err(busy.to_string())) {
+            Ok(frame) => match socket.write_all(&frame).await {
+                Ok(()) => socket.shutdown().await.map_err(KvsError::from),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            error!("Failed to send response: {e}");
+        }
+    }
+
+    async fn serve(
+        socket: TcpStream,
+        store: impl AsyncKvsEngine,
+        max_len: u32,
+        idle_timeout: Option<Duration>,
+    ) {
+        let (reader, mut writer) = socket.into_split();
+        let mut reader = BufReader::new(reader);
+
+        loop {
+            let read = try_read_frame(&mut reader, max_len);
+            let read = match idle_timeout {
+                Some(idle_timeout) => match tokio::time::timeout(idle_timeout, read).await {
+                    Ok(read) => read,
+                    Err(_) => {
+                        info!("Closing an idle connection");
+                        break;
+                    }
+                },
+                None => read.await,
+            };
+            let incoming: IncomingRequest = match read {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => break,
+                Err(e @ KvsError::FrameTooLarge { .. }) => {
+                    // the body is left unread, so the connection can't carry another request
+                    warn!("Oversized request, closing the connection: {e}");
+                    let sent = match encode_frame(&Response::Err(e.to_string())) {
+                        Ok(frame) => writer.write_all(&frame).await.map_err(KvsError::from),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = sent {
+                        error!("Failed to send response: {e}");
+                    }
+                    break;
+                }
+                Err(e) => {
+                    warn!("Malformed request, closing the connection: {e}");
+                    break;
+                }
+            };
+            debug!("{:?}", incoming);
+            let response = match Request::try_from(incoming) {
+                Ok(request) => Self::handle(&store, request).await,
+                Err(e) => Response::Err(e.to_string()),
+            };
+            let sent = match encode_frame(&response) {
+                Ok(frame) => writer.write_all(&frame).await.map_err(KvsError::from),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                error!("Failed to send response: {e}");
+                break;
+            }
+        }
+    }
+
+    async fn handle(store: &impl AsyncKvsEngine, request: Request) -> Response {
+        match request {
+            Request::Get { key } => match store.get(key).await {
+                Ok(value) => Response::Ok(value),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Set { key, value } => match store.set(key, value).await {
+                Ok(previous) => Response::Ok(previous),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Remove { key } => match store.remove(key.clone()).await {
+                Ok(Some(previous)) => Response::Ok(Some(previous)),
+                Ok(None) => {
+                    warn!("NO such key in storage: {}", key);
+                    Response::KeyNotFound
+                }
+                Err(e) => Response::Err(e.to_string()),
+            },
+            _ => Response::Err(
+                KvsError::Unsupported(
+                    "the async server only serves get, set and remove".to_owned(),
+                )
+                .to_string(),
+            ),
+        }
+    }
+}
+
+/// Async counterpart of `proto::try_read_frame_within`.
+async fn try_read_frame<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+    max_len: u32,
+) -> Result<Option<T>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => {
+                return Err(
+                    io::Error::new(ErrorKind::UnexpectedEof, "Truncated frame header").into(),
+                )
+            }
+            n => filled += n,
+        }
+    }
+    let Some(body_len) = body_len(header, max_len)? else {
+        return Ok(None);
+    };
+    // grow with the bytes that actually arrive rather than trusting the header
+    let mut body = Vec::with_capacity(body_len.min(INITIAL_BODY_CAPACITY));
+    reader.take(body_len as u64).read_to_end(&mut body).await?;
+    if body.len() < body_len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "Truncated frame body").into());
+    }
+    Ok(Some(serde_json::from_slice(&body)?))
+}
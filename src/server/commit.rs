@@ -0,0 +1,76 @@
+use crate::{KvsEngine, Result};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct CommitState {
+    /// Writes handed to `commit` so far, each numbered by its position.
+    written: u64,
+    /// Writes up to which a sync has succeeded.
+    synced: u64,
+    /// Whether a connection is gathering a batch or syncing it.
+    leading: bool,
+}
+
+/// Group commit of the writes of all connections.
+///
+/// A connection calls `commit` once its write has reached the engine. The first caller
+/// leads a batch: it waits up to `window`, or until `max_batch` writes are waiting, then
+/// syncs the engine once for all of them. Callers whose write the sync covered return
+/// with the leader; the rest wait for the next batch.
+pub(crate) struct GroupCommit {
+    window: Duration,
+    max_batch: u64,
+    state: Mutex<CommitState>,
+    changed: Condvar,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(window: Duration, max_batch: usize) -> GroupCommit {
+        GroupCommit {
+            window,
+            max_batch: max_batch.max(1) as u64,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Wait until a sync of `store` covers a write that has just returned.
+    ///
+    /// A failed sync is returned to its leader only. The writes it covered stay waiting,
+    /// and one of them leads the next try.
+    pub(crate) fn commit(&self, store: &impl KvsEngine) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        let ticket = state.written;
+        // a leader gathering a batch may be waiting for it to fill
+        self.changed.notify_all();
+        while state.leading {
+            state = self.changed.wait(state).unwrap();
+            if state.synced >= ticket {
+                return Ok(());
+            }
+        }
+
+        state.leading = true;
+        let deadline = Instant::now() + self.window;
+        while state.written - state.synced < self.max_batch {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        let target = state.written;
+        drop(state);
+
+        let synced = store.sync();
+        let mut state = self.state.lock().unwrap();
+        if synced.is_ok() {
+            state.synced = state.synced.max(target);
+        }
+        state.leading = false;
+        self.changed.notify_all();
+        synced
+    }
+}
@@ -0,0 +1,28 @@
+use crate::proto::Response;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of the requests a `KvServer` has answered, shared by all its connections.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl ServerMetrics {
+    /// Requests answered, including those answered with an error.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered with `Response::Err`. A missing key is not an error.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, response: &Response) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Response::Err(_) = response {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+#[cfg(feature = "tokio")]
+pub mod async_server;
+mod commit;
+mod metrics;
+
+pub use metrics::ServerMetrics;
+
+use crate::cache::ReadCache;
+use crate::engines::{DEFAULT_MAX_KEY_SIZE, DEFAULT_MAX_VALUE_SIZE};
+use crate::proto::{
+    max_request_len, try_read_frame_within, write_frame, IncomingRequest, KeyResult, Request,
+    Response,
+};
+use crate::{KvsEngine, KvsError, Limits, Result, ThreadPool};
+use commit::GroupCommit;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Current layout of `config.json`. Files without a `version` field predate it.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// The `max_connections` every version 1 config was saved with, chosen or not.
+const V1_MAX_CONNECTIONS: usize = 1024;
+
+/// Server settings persisted as `config.json` in the data directory.
+///
+/// Every field but `engine` has a serde default, so config files written by older
+/// versions still load and are upgraded in place.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub version: u32,
+    pub engine: String,
+    #[serde(default = "default_thread_pool")]
+    pub thread_pool: String,
+    #[serde(default = "default_worker_num")]
+    pub worker_num: u32,
+    #[serde(default = "default_addr")]
+    pub addr: String,
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    /// Number of `get` results cached in front of the engine, 0 to disable the cache.
+    #[serde(default)]
+    pub cache_capacity: usize,
+    /// How long a cached `get` result is served before the engine is read again.
+    #[serde(default = "default_cache_ttl_ms")]
+    pub cache_ttl_ms: u64,
+    /// Largest key the engine accepts, in bytes.
+    #[serde(default = "default_max_key_size")]
+    pub max_key_size: usize,
+    /// Largest value the engine accepts, in bytes.
+    #[serde(default = "default_max_value_size")]
+    pub max_value_size: usize,
+    /// Connections served at once, one per worker if `None`. Connections past it are
+    /// answered with a `KvsError::ServerBusy` error and closed.
+    ///
+    /// A connection holds its worker until it closes or goes idle, so more connections
+    /// than workers only queue up behind the busy ones.
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /// How long a write waits for others to share its engine sync, 0 to disable group
+    /// commit and leave durability to the engine.
+    #[serde(default)]
+    pub commit_window_ms: u64,
+    /// Writes that end the commit window early once they are all waiting.
+    #[serde(default = "default_commit_batch_size")]
+    pub commit_batch_size: usize,
+    /// How long a connection may wait for its next request before it is closed, 0 to
+    /// keep idle connections open. An idle connection holds a worker of the pool.
+    #[serde(default = "default_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+}
+
+fn default_thread_pool() -> String {
+    "shared_queue".to_string()
+}
+
+fn default_worker_num() -> u32 {
+    8
+}
+
+fn default_addr() -> String {
+    "127.0.0.1:4000".to_string()
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_cache_ttl_ms() -> u64 {
+    100
+}
+
+fn default_max_key_size() -> usize {
+    DEFAULT_MAX_KEY_SIZE
+}
+
+fn default_max_value_size() -> usize {
+    DEFAULT_MAX_VALUE_SIZE
+}
+
+fn default_commit_batch_size() -> usize {
+    64
+}
+
+fn default_idle_timeout_ms() -> u64 {
+    30_000
+}
+
+impl ServerConfig {
+    pub fn new(engine: String) -> ServerConfig {
+        ServerConfig {
+            version: CONFIG_VERSION,
+            engine,
+            thread_pool: default_thread_pool(),
+            worker_num: default_worker_num(),
+            addr: default_addr(),
+            data_dir: default_data_dir(),
+            cache_capacity: 0,
+            cache_ttl_ms: default_cache_ttl_ms(),
+            max_key_size: default_max_key_size(),
+            max_value_size: default_max_value_size(),
+            max_connections: None,
+            commit_window_ms: 0,
+            commit_batch_size: default_commit_batch_size(),
+            idle_timeout_ms: default_idle_timeout_ms(),
+        }
+    }
+
+    /// Connections `KvServer` serves at once.
+    pub fn connection_limit(&self) -> usize {
+        self.max_connections.unwrap_or(self.worker_num as usize)
+    }
+
+    /// How long a connection may sit idle, `None` for no limit.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_ms > 0).then(|| Duration::from_millis(self.idle_timeout_ms))
+    }
+
+    /// Size limits to open the engine with.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+        }
+    }
+
+    /// Load a config file, upgrading it on disk if it was written by an older version.
+    pub fn load(path: impl AsRef<Path>) -> Result<ServerConfig> {
+        let value = std::fs::read_to_string(path.as_ref())?;
+        let mut config: ServerConfig = serde_json::from_str(&value)?;
+        if config.version < CONFIG_VERSION {
+            // the old default was saved as if chosen, let it follow the workers instead
+            if config.version < 2 && config.max_connections == Some(V1_MAX_CONNECTIONS) {
+                config.max_connections = None;
+            }
+            config.version = CONFIG_VERSION;
+            config.save(path)?;
+        }
+        Ok(config)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let value = serde_json::to_string_pretty(self)?;
+        let mut f = File::create(path.as_ref())?;
+        f.write_all(value.as_bytes())?;
+        f.flush()?;
+        Ok(())
+    }
+}
+
+pub struct KvServer {
+    config: ServerConfig,
+    cache: Option<Arc<ReadCache>>,
+    metrics: Arc<ServerMetrics>,
+    /// Connections accepted and not yet closed, queued for a worker or being served.
+    connections: Arc<AtomicUsize>,
+    commit: Option<Arc<GroupCommit>>,
+}
+
+/// Held by an accepted connection, frees its place under `max_connections` when
+/// `serve` returns or panics.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Count a new connection in `connections`, or return `None` if `max` are open.
+    fn reserve(connections: &Arc<AtomicUsize>, max: usize) -> Option<ConnectionSlot> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(connections)))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl KvServer {
+    pub fn new(config: ServerConfig) -> KvServer {
+        let cache = (config.cache_capacity > 0).then(|| {
+            Arc::new(ReadCache::new(
+                config.cache_capacity,
+                Duration::from_millis(config.cache_ttl_ms),
+            ))
+        });
+        let commit = (config.commit_window_ms > 0).then(|| {
+            Arc::new(GroupCommit::new(
+                Duration::from_millis(config.commit_window_ms),
+                config.commit_batch_size,
+            ))
+        });
+        KvServer {
+            config,
+            cache,
+            metrics: Arc::default(),
+            connections: Arc::default(),
+            commit,
+        }
+    }
+
+    /// Counters of the requests answered so far, updated as the server runs.
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Connections accepted and not yet closed.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Tell a client past `max_connections` that the server is busy, then hang up.
+    fn reject(&self, mut socket: TcpStream) {
+        let busy = KvsError::ServerBusy {
+            max: self.config.connection_limit(),
+        };
+        match socket.peer_addr() {
+            Ok(addr) => warn!("Refusing client {addr}: {busy}"),
+            Err(_) => warn!("Refusing a client: {busy}"),
+        }
+        let sent = write_frame(&mut socket, &Response::Err(busy.to_string()))
+            .and_then(|_| Ok(socket.shutdown(Shutdown::Write)?));
+        if let Err(e) = sent {
+            error!("Failed to send response: {e}");
+        }
+    }
+
+    fn serve(
+        socket: TcpStream,
+        store: impl KvsEngine,
+        cache: Option<Arc<ReadCache>>,
+        commit: Option<Arc<GroupCommit>>,
+        metrics: Arc<ServerMetrics>,
+        max_len: u32,
+        idle_timeout: Option<Duration>,
+    ) {
+        match socket.peer_addr() {
+            Ok(addr) => info!("New client: {addr}"),
+            Err(e) => warn!("New client with unknown address: {e}"),
+        }
+        // shared with the clone below, and keeps an idle client from holding the worker
+        if let Err(e) = socket.set_read_timeout(idle_timeout) {
+            error!("Failed to set up the connection: {e}");
+            return;
+        }
+
+        let mut reader = match socket.try_clone() {
+            Ok(socket) => BufReader::new(socket),
+            Err(e) => {
+                error!("Failed to set up the connection: {e}");
+                return;
+            }
+        };
+        let mut writer = BufWriter::new(socket);
+
+        // one connection carries requests until the peer hangs up or ends the session
+        loop {
+            let incoming: IncomingRequest = match try_read_frame_within(&mut reader, max_len) {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => break,
+                Err(e @ KvsError::FrameTooLarge { .. }) => {
+                    // the body is left unread, so the connection can't carry another request
+                    warn!("Oversized request, closing the connection: {e}");
+                    if let Err(e) = write_frame(&mut writer, &Response::Err(e.to_string())) {
+                        error!("Failed to send response: {e}");
+                    }
+                    break;
+                }
+                Err(KvsError::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    info!("Closing an idle connection");
+                    break;
+                }
+                Err(e) => {
+                    warn!("Malformed request, closing the connection: {e}");
+                    break;
+                }
+            };
+            debug!("{:?}", incoming);
+            let response = match Request::try_from(incoming) {
+                Ok(request) => {
+                    let started = Instant::now();
+                    let kind = request.kind();
+                    let key = request.key().unwrap_or("-").to_owned();
+                    let write = request.is_write();
+                    let mut response = Self::handle(&store, cache.as_deref(), request);
+                    // answer a write only once it is durable
+                    if let Some(commit) = commit.as_deref().filter(|_| write) {
+                        if !matches!(response, Response::Err(_)) {
+                            if let Err(e) = commit.commit(&store) {
+                                error!("Failed to sync {kind} {key}: {e}");
+                                response = Response::Err(e.to_string());
+                            }
+                        }
+                    }
+                    debug!(
+                        "{kind} {key}: {} in {:?}",
+                        Self::outcome(&response),
+                        started.elapsed()
+                    );
+                    response
+                }
+                Err(e) => Response::Err(e.to_string()),
+            };
+            metrics.record(&response);
+            if let Err(e) = write_frame(&mut writer, &response) {
+                error!("Failed to send response: {e}");
+                break;
+            }
+        }
+    }
+
+    fn handle(store: &impl KvsEngine, cache: Option<&ReadCache>, request: Request) -> Response {
+        // invalidate even when the write failed, it may have reached the engine anyway
+        if let Some(cache) = cache {
+            if request == Request::Clear {
+                let response = Self::handle(store, None, request);
+                cache.invalidate_all();
+                return response;
+            }
+            let written = Self::written_keys(&request);
+            if !written.is_empty() {
+                let response = Self::handle(store, None, request);
+                for key in written {
+                    cache.invalidate(&key);
+                }
+                return response;
+            }
+        }
+        match request {
+            Request::Set { key, value } => match store.set(key, value) {
+                Ok(previous) => Response::Ok(previous),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Get { key } => match Self::cached_get(store, cache, key.clone()) {
+                Ok(value) => {
+                    if value.is_none() {
+                        warn!("NO such key in storage: {}", key);
+                    }
+                    Response::Ok(value)
+                }
+                Err(e) => {
+                    error!("Failed to get {}: {e}", key);
+                    Response::Err(e.to_string())
+                }
+            },
+            Request::Remove { key } => match store.remove_opt(key.clone()) {
+                Ok(Some(previous)) => Response::Ok(Some(previous)),
+                Ok(None) => {
+                    warn!("NO such key in storage: {}", key);
+                    Response::KeyNotFound
+                }
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Scan { after, limit } => match store
+                .cursor(after)
+                .and_then(|cursor| cursor.take(limit.unwrap_or(usize::MAX)).collect())
+            {
+                Ok(entries) => Response::Entries(entries),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Batch { batch } => match store.write_batch(batch) {
+                Ok(_) => Response::Ok(None),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Cas { key, expected, new } => match store.cas(key, expected, new) {
+                Ok(swapped) => Response::Swapped(swapped),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::GetMany { keys } => Response::Values(Self::get_each(store, keys)),
+            Request::Exists { key } => match store.contains(key) {
+                Ok(exists) => Response::Exists(exists),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Clear => match store.clear() {
+                Ok(()) => Response::Ok(None),
+                Err(e) => Response::Err(e.to_string()),
+            },
+            Request::Stats => match store.stats() {
+                Ok(stats) => Response::Stats(stats),
+                Err(e) => Response::Err(e.to_string()),
+            },
+        }
+    }
+
+    /// How a request went, for the request log.
+    fn outcome(response: &Response) -> &'static str {
+        match response {
+            Response::KeyNotFound => "not found",
+            Response::Err(_) => "error",
+            _ => "ok",
+        }
+    }
+
+    /// Keys a request writes to, which must be dropped from the read cache.
+    fn written_keys(request: &Request) -> Vec<String> {
+        match request {
+            Request::Set { key, .. } | Request::Remove { key } | Request::Cas { key, .. } => {
+                vec![key.clone()]
+            }
+            Request::Batch { batch } => batch.records().iter().map(|r| r.key.clone()).collect(),
+            // handled by `handle`, which drops the whole cache
+            Request::Clear => Vec::new(),
+            Request::Get { .. }
+            | Request::GetMany { .. }
+            | Request::Exists { .. }
+            | Request::Scan { .. }
+            | Request::Stats => Vec::new(),
+        }
+    }
+
+    /// Values of `keys` with a status each. If the engine fails the whole batch, the keys
+    /// are read again one by one, so only the keys that fail report an error.
+    fn get_each(store: &impl KvsEngine, keys: Vec<String>) -> Vec<KeyResult> {
+        match store.get_many(keys.clone()) {
+            Ok(values) => values.into_iter().map(|value| Ok(value).into()).collect(),
+            Err(_) => keys.into_iter().map(|key| store.get(key).into()).collect(),
+        }
+    }
+
+    fn cached_get(
+        store: &impl KvsEngine,
+        cache: Option<&ReadCache>,
+        key: String,
+    ) -> Result<Option<String>> {
+        let Some(cache) = cache else {
+            return store.get(key);
+        };
+        if let Some(value) = cache.get(&key) {
+            return Ok(value);
+        }
+        let epoch = cache.begin_read();
+        let value = store.get(key.clone())?;
+        cache.fill(key, value.clone(), epoch);
+        Ok(value)
+    }
+
+    /// Bind the configured address and serve connections until the listener fails.
+    pub fn start(&self, store: impl KvsEngine, pool: impl ThreadPool) -> Result<()> {
+        let engine = &self.config.engine;
+        let ip = &self.config.addr;
+        info!(env!("CARGO_PKG_VERSION"));
+        info!("ENGINE: {engine}, IP: {ip}");
+
+        self.run(TcpListener::bind(ip)?, store, pool)
+    }
+
+    /// Serve connections accepted by `listener`, each on a worker of `pool` with its own
+    /// clone of `store`.
+    pub fn run(
+        &self,
+        listener: TcpListener,
+        store: impl KvsEngine,
+        pool: impl ThreadPool,
+    ) -> Result<()> {
+        // report the bound address, which differs from the configured one for port 0
+        info!("Listen at {}", listener.local_addr()?);
+        // no request within the engine's limits is larger
+        let max_len = max_request_len(self.config.limits());
+        let idle_timeout = self.config.idle_timeout();
+
+        for socket in listener.incoming() {
+            match socket {
+                Ok(socket) => {
+                    let max = self.config.connection_limit();
+                    let Some(slot) = ConnectionSlot::reserve(&self.connections, max) else {
+                        self.reject(socket);
+                        continue;
+                    };
+                    let n_store = store.clone();
+                    let cache = self.cache.clone();
+                    let commit = self.commit.clone();
+                    let metrics = self.metrics();
+                    pool.spawn(move || {
+                        let _slot = slot;
+                        Self::serve(
+                            socket,
+                            n_store,
+                            cache,
+                            commit,
+                            metrics,
+                            max_len,
+                            idle_timeout,
+                        )
+                    })
+                }
+                Err(e) => error!("Failed to accept a connection: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+}
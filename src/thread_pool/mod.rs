@@ -2,11 +2,12 @@ pub mod naive;
 pub mod rayon;
 pub mod shared_queue;
 
+pub use crate::thread_pool::rayon::RayonThreadPool;
 pub use naive::NaiveThreadPool;
 pub use shared_queue::SharedQueueThreadPool;
-pub use crate::thread_pool::rayon::RayonThreadPool;
 
 use crate::Result;
+use std::sync::mpsc::{self, Receiver};
 
 pub trait ThreadPool {
     fn new(_worker_num: u32) -> Result<Self>
@@ -15,4 +16,22 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Like `spawn`, but the job's result is sent to the returned receiver.
+    ///
+    /// If the job panics, `recv` fails instead of returning a result, unless the panic
+    /// takes the process down, as it does in `RayonThreadPool`.
+    fn spawn_handle<T, F>(&self, job: F) -> Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // room for the one result, so the job never waits for the caller to receive it
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.spawn(move || {
+            // the caller may have dropped the receiver, not wanting the result
+            sender.send(job()).ok();
+        });
+        receiver
+    }
 }
@@ -1,20 +1,76 @@
-use std::thread;
+use log::error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
 
-use crate::{Result, ThreadPool};
+use crate::{KvsError, Result, ThreadPool};
 
+/// Spawns a new thread for every job, with at most `worker_num` running at once.
+///
+/// `spawn` blocks while the cap is reached. Dropping the pool waits for every thread it
+/// spawned.
 pub struct NaiveThreadPool {
-    _worker_num: u32,
+    worker_num: u32,
+    running: Arc<(Mutex<u32>, Condvar)>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+/// Held by a job's thread, frees its place under the cap when the job ends or panics.
+struct Slot(Arc<(Mutex<u32>, Condvar)>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.0;
+        *count.lock().unwrap() -= 1;
+        freed.notify_one();
+    }
 }
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_worker_num: u32) -> Result<NaiveThreadPool> {
-        Ok(NaiveThreadPool { _worker_num })
+    fn new(worker_num: u32) -> Result<NaiveThreadPool> {
+        if worker_num == 0 {
+            return Err(KvsError::ThreadPool(
+                "a pool needs at least one thread".to_owned(),
+            ));
+        }
+        Ok(NaiveThreadPool {
+            worker_num,
+            running: Arc::new((Mutex::new(0), Condvar::new())),
+            handles: Arc::new(Mutex::new(Vec::new())),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let (count, freed) = &*self.running;
+        let mut running = count.lock().unwrap();
+        while *running >= self.worker_num {
+            running = freed.wait(running).unwrap();
+        }
+        *running += 1;
+        drop(running);
+
+        let slot = Slot(Arc::clone(&self.running));
+        let handle = thread::spawn(move || {
+            let _slot = slot;
+            job()
+        });
+
+        let mut handles = self.handles.lock().unwrap();
+        // forget threads that already exited so the list doesn't grow with every job
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+    }
+}
+
+impl Drop for NaiveThreadPool {
+    fn drop(&mut self) {
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        for handle in handles {
+            if handle.join().is_err() {
+                error!("Job thread panicked");
+            }
+        }
     }
 }
@@ -1,15 +1,22 @@
-use crate::{ThreadPool, Result};
+use crate::{KvsError, Result, ThreadPool};
 
 /// Wrapper of rayon::ThreadPool
-pub struct RayonThreadPool{
+pub struct RayonThreadPool {
     inner: rayon::ThreadPool,
 }
 
 impl ThreadPool for RayonThreadPool {
+    /// Fails for 0 threads, which rayon would take as "one per CPU".
     fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::ThreadPool(
+                "a pool needs at least one thread".to_owned(),
+            ));
+        }
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
-            .build().unwrap();
+            .build()
+            .map_err(|e| KvsError::ThreadPool(e.to_string()))?;
         Ok(RayonThreadPool { inner: pool })
     }
 
@@ -19,4 +26,4 @@ impl ThreadPool for RayonThreadPool {
     {
         self.inner.spawn(job)
     }
-}
\ No newline at end of file
+}
@@ -1,31 +1,45 @@
-use std::thread;
-use std::sync::mpsc::{self, Sender, Receiver};
+use log::{debug, error};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
-use serde_json::error;
-use std::process::exit;
-use log::debug;
+use std::thread::{self, JoinHandle};
 
-use crate::{Result, ThreadPool};
+use crate::{KvsError, Result, ThreadPool};
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of workers taking jobs from one shared channel.
+///
+/// A panicking job takes its worker down, and a new worker takes its place on the same
+/// channel. Dropping the pool closes the channel, lets the workers finish the jobs
+/// already queued and waits for them to exit.
 pub struct SharedQueueThreadPool {
     _worker_num: u32,
-    producer: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    producer: Option<Sender<Job>>,
+    /// Handles of the workers started so far, replacements included.
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(worker_num: u32) -> Result<SharedQueueThreadPool> {
+        // without workers every job would wait in the queue forever
+        if worker_num == 0 {
+            return Err(KvsError::ThreadPool(
+                "a pool needs at least one thread".to_owned(),
+            ));
+        }
         let (producer, consumer) = mpsc::channel();
-        let consumer = Arc::new(Mutex::new(consumer));
+        let worker = Worker {
+            consumer: Arc::new(Mutex::new(consumer)),
+            handles: Arc::new(Mutex::new(Vec::with_capacity(worker_num as usize))),
+        };
         for _ in 0..worker_num {
-            let n_consumer = Arc::clone(&consumer);
-            thread::spawn(move||{
-                worker_loop(n_consumer);
-            });
+            worker.clone().start()?;
         }
         Ok(SharedQueueThreadPool {
             _worker_num: worker_num,
-            producer,
+            producer: Some(producer),
+            workers: Arc::clone(&worker.handles),
         })
     }
 
@@ -34,19 +48,67 @@ impl ThreadPool for SharedQueueThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let handle = Box::new(job);
-        self.producer.send(handle).unwrap();
+        self.producer
+            .as_ref()
+            .expect("pool is shutting down")
+            .send(handle)
+            .unwrap();
     }
 }
 
-fn worker_loop(consumer: Arc<Mutex<Receiver<Box<dyn FnOnce() + Send + 'static>>>>) {
-    loop {
-        match consumer.lock().unwrap().recv() {
-            Ok(job) => {
-                job();
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // closing the channel ends each worker once the queue is drained
+        drop(self.producer.take());
+        // a worker that panics while we wait pushes its replacement before it exits
+        loop {
+            let Some(worker) = self.workers.lock().unwrap().pop() else {
+                break;
+            };
+            if worker.join().is_err() {
+                debug!("Joined a worker taken down by a panicking job");
             }
-            Err(_) => {
-                debug!("Error fetching jobs");
-                exit(0);
+        }
+    }
+}
+
+/// A worker thread's share of the pool. Dropped while unwinding from a panicking job, it
+/// starts a replacement worker on the same channel.
+#[derive(Clone)]
+struct Worker {
+    consumer: Arc<Mutex<Receiver<Job>>>,
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Worker {
+    fn start(self) -> std::io::Result<()> {
+        let handles = Arc::clone(&self.handles);
+        let handle = thread::Builder::new().spawn(move || self.run())?;
+        handles.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    fn run(self) {
+        loop {
+            // release the lock before running the job so other workers can take the next one
+            let job = self.consumer.lock().unwrap().recv();
+            match job {
+                Ok(job) => job(),
+                Err(_) => {
+                    debug!("Job queue closed, worker exiting");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            debug!("Job panicked, starting a replacement worker");
+            if let Err(e) = self.clone().start() {
+                error!("Failed to replace a worker: {e}");
             }
         }
     }
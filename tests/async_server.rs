@@ -0,0 +1,91 @@
+#![cfg(feature = "tokio")]
+
+use kvs::client::KvsClient;
+use kvs::proto::{read_frame, Response};
+use kvs::server::async_server::{AsyncKvServer, SpawnBlocking};
+use kvs::server::ServerConfig;
+use kvs::{KvStore, Result};
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+// 100 clients connected at once should each get their own writes back
+#[test]
+fn async_server_concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SpawnBlocking(KvStore::open(temp_dir.path())?);
+    let runtime = Runtime::new()?;
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    let server = AsyncKvServer::new(ServerConfig::new("kvs".to_owned()));
+    runtime.spawn(async move { server.run(listener, store).await });
+
+    let clients: Vec<_> = (0..100)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                let key = format!("key{i}");
+                let value = format!("value{i}");
+                assert_eq!(client.set(key.clone(), value.clone())?, None);
+                assert_eq!(client.get(key.clone())?, Some(value.clone()));
+                assert_eq!(client.remove(key.clone())?, value);
+                assert_eq!(client.get(key)?, None);
+                Ok(())
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// A client past `max_connections` should be told the server is busy and disconnected
+#[test]
+fn async_server_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SpawnBlocking(KvStore::open(temp_dir.path())?);
+    let runtime = Runtime::new()?;
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.max_connections = Some(1);
+    let server = AsyncKvServer::new(config);
+    runtime.spawn(async move { server.run(listener, store).await });
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    let mut socket = TcpStream::connect(addr)?;
+    assert!(matches!(read_frame(&mut socket)?, Response::Err(_)));
+    assert_eq!(socket.read(&mut [0; 1])?, 0);
+    assert_eq!(client.set("key1".to_owned(), "value1".to_owned())?, None);
+    Ok(())
+}
+
+// A client that stops sending requests should be disconnected after the idle timeout
+#[test]
+fn async_server_idle_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SpawnBlocking(KvStore::open(temp_dir.path())?);
+    let runtime = Runtime::new()?;
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0"))?;
+    let addr = listener.local_addr()?;
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.idle_timeout_ms = 200;
+    let server = AsyncKvServer::new(config);
+    runtime.spawn(async move { server.run(listener, store).await });
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    let mut socket = TcpStream::connect(addr)?;
+    // fail instead of hanging if the connection is never closed
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(socket.read(&mut [0; 1])?, 0);
+    thread::sleep(Duration::from_millis(300));
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
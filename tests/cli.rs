@@ -1,4 +1,6 @@
 use assert_cmd::prelude::*;
+use kvs::server::ServerConfig;
+use kvs::{KvStore, KvsClient, KvsEngine, SledStore};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +147,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +159,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +181,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,39 +201,305 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
     }
 }
 
+// `kvs-server` should refuse an unknown engine before touching the data directory
+#[test]
+fn cli_invalid_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "fancy", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid value 'fancy'"));
+    assert!(!temp_dir.path().join("config.json").exists());
+}
+
+// `kvs-server` should refuse an unknown thread pool or a non-numeric worker count
+#[test]
+fn cli_invalid_thread_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--thread-pool", "fancy", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid value 'fancy'"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--worker-num", "many", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // a hand-edited config.json goes through the same check
+    fs::write(
+        temp_dir.path().join("config.json"),
+        r#"{"engine":"kvs","thread_pool":"fancy"}"#,
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unknown thread pool \"fancy\""));
+}
+
+// The thread pool and worker count saved in config.json should survive a restart
+// without `--thread-pool` and `--worker-num`
+#[test]
+fn cli_thread_pool_persisted() {
+    let temp_dir = TempDir::new().unwrap();
+    let start = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--addr", "127.0.0.1:4019"])
+            .args(args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
+    };
+
+    start(&["--thread-pool", "rayon", "--worker-num", "16"]);
+    start(&[]);
+    let config = ServerConfig::load(temp_dir.path().join("config.json")).unwrap();
+    assert_eq!(config.thread_pool, "rayon");
+    assert_eq!(config.worker_num, 16);
+
+    // a flag still overrides the saved value
+    start(&["--worker-num", "2"]);
+    let config = ServerConfig::load(temp_dir.path().join("config.json")).unwrap();
+    assert_eq!(config.thread_pool, "rayon");
+    assert_eq!(config.worker_num, 2);
+}
+
+// `kvs-server --engine auto` should use kvs on a fresh directory, the persisted engine
+// otherwise, and fail when the persisted data contradicts `config.json`.
+#[test]
+fn cli_auto_engine() {
+    // fresh directory
+    {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--engine", "auto", "--addr", "127.0.0.1:4006"])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
+
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        assert!(content.contains("ENGINE: kvs"));
+    }
+
+    // existing sled directory
+    {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4007"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
+
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--engine", "auto", "--addr", "127.0.0.1:4007"])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
+
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        assert!(content.contains("ENGINE: sled"));
+
+        // sled data on disk but config.json claims kvs
+        fs::write(temp_dir.path().join("config.json"), r#"{"engine":"kvs"}"#).unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--engine", "auto", "--addr", "127.0.0.1:4008"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+}
+
+// `kvs-server` without `--engine` should keep using the persisted engine, and reject a
+// different one with a labeled error even when `config.json` is gone.
+#[test]
+fn cli_default_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("ENGINE: sled"));
+
+    fs::remove_file(temp_dir.path().join("config.json")).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine: expected kvs, found sled"));
+}
+
+// Without `--engine`, a fresh directory and one holding kvs data should both use kvs,
+// and asking for sled over kvs data should fail with a labeled error.
+#[test]
+fn cli_default_engine_kvs_data() {
+    let temp_dir = TempDir::new().unwrap();
+    for _ in 0..2 {
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = cmd
+            .args(["--addr", "127.0.0.1:4012"])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        assert!(content.contains("ENGINE: kvs"));
+        assert!(temp_dir.path().join("log").is_file());
+    }
+
+    fs::remove_file(temp_dir.path().join("config.json")).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine: expected sled, found kvs"));
+}
+
+// A malformed `--addr` should fail up front with a message naming the address
+#[test]
+fn cli_invalid_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "not-an-addr"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid address \"not-an-addr\""));
+
+    // IPv6 addresses need brackets to tell the port apart
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "::1:4014"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid address \"::1:4014\""));
+    assert!(!temp_dir.path().join("config.json").exists());
+}
+
+// Bracketed IPv6 addresses should work for both the server and the client
+#[test]
+fn cli_ipv6_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "[::1]:4013"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "[::1]:4013"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "[::1]:4013"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +507,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +515,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +523,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +531,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +539,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +547,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +555,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,30 +568,67 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server was never reaped");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key2", "key1", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\nKey not found\nvalue3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("true\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["exists", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("false\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["clear", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -335,3 +642,299 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// The library client and kvs-client should see each other's writes on a real server, and
+// server errors should reach the CLI
+#[test]
+fn cli_library_client() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4015").unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(client.remove("key2".to_owned()).unwrap(), "value2");
+
+    // over the default 64 KiB key limit
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            &"k".repeat(64 * 1024 + 1),
+            "value",
+            "--addr",
+            "127.0.0.1:4015",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key of 65537 bytes"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+}
+
+// `--data-dir` should hold the server's data and config.json instead of the working
+// directory
+#[test]
+fn cli_server_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let work_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4016", "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&work_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4016"])
+        .current_dir(&work_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+
+    assert!(data_dir.join("config.json").is_file());
+    assert!(data_dir.join("log").is_file());
+    assert!(fs::read_dir(&work_dir).unwrap().next().is_none());
+}
+
+// A first start with `--import-from` should serve the data of a sled store from a new
+// kvs store, and later starts shouldn't import again
+#[test]
+fn cli_import_from() {
+    let sled_dir = TempDir::new().unwrap();
+    let source = SledStore::open(sled_dir.path()).unwrap();
+    for key_id in 0..100 {
+        source
+            .set(format!("key{key_id}"), format!("value{key_id}"))
+            .unwrap();
+    }
+    drop(source);
+
+    let temp_dir = TempDir::new().unwrap();
+    let start = || {
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let child = server
+            .args([
+                "--engine",
+                "kvs",
+                "--addr",
+                "127.0.0.1:4018",
+                "--import-from",
+            ])
+            .arg(sled_dir.path())
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let mut child = start();
+    let mut client = KvsClient::connect("127.0.0.1:4018").unwrap();
+    for key_id in 0..100 {
+        assert_eq!(
+            client.get(format!("key{key_id}")).unwrap(),
+            Some(format!("value{key_id}"))
+        );
+    }
+    client.remove("key0".to_owned()).unwrap();
+    drop(client);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+
+    let mut child = start();
+    let mut client = KvsClient::connect("127.0.0.1:4018").unwrap();
+    assert_eq!(client.get("key0".to_owned()).unwrap(), None);
+    drop(client);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.approx_key_count().unwrap(), 99);
+
+    // the source has to hold data of the engine it is given as
+    let empty_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--import-engine", "kvs", "--import-from"])
+        .arg(sled_dir.path())
+        .current_dir(&empty_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine: expected kvs, found sled"));
+}
+
+// A start after an interrupted import should refuse the partial data, and finish the
+// import when given the source again
+#[test]
+fn cli_import_interrupted() {
+    let source_dir = TempDir::new().unwrap();
+    let source = KvStore::open(source_dir.path()).unwrap();
+    for key_id in 0..100 {
+        source
+            .set(format!("key{key_id}"), format!("value{key_id}"))
+            .unwrap();
+    }
+    drop(source);
+
+    // what an import killed partway leaves behind
+    let temp_dir = TempDir::new().unwrap();
+    let partial = KvStore::open(temp_dir.path()).unwrap();
+    for key_id in 0..10 {
+        partial
+            .set(format!("key{key_id}"), format!("value{key_id}"))
+            .unwrap();
+    }
+    drop(partial);
+    fs::write(temp_dir.path().join("import.partial"), "").unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4020"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("was interrupted"));
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4020", "--import-from"])
+        .arg(source_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut client = KvsClient::connect("127.0.0.1:4020").unwrap();
+    for key_id in 0..100 {
+        assert_eq!(
+            client.get(format!("key{key_id}")).unwrap(),
+            Some(format!("value{key_id}"))
+        );
+    }
+    drop(client);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+    assert!(!temp_dir.path().join("import.partial").exists());
+}
+
+// `dump` then `load` should bring back removed keys with their dumped values
+#[test]
+fn cli_dump_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4017"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", "127.0.0.1:4017"])
+            .current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key2", "value2"]).assert().success();
+    client(&["dump", "dump.json"]).assert().success();
+    client(&["rm", "key1"]).assert().success();
+    client(&["set", "key2", "changed"]).assert().success();
+    client(&["load", "dump.json"]).assert().success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("server was never reaped");
+    let dump = fs::read_to_string(temp_dir.path().join("dump.json")).unwrap();
+    assert_eq!(dump.lines().count(), 2);
+}
+
+// `migrate` should copy every key into a sled directory a server can then start from, and
+// refuse to copy over existing data without --force
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("kvs");
+    let dest = temp_dir.path().join("sled");
+    fs::create_dir(&source).unwrap();
+    let store = KvStore::open(&source).unwrap();
+    for key_id in 0..100 {
+        store
+            .set(format!("key{key_id}"), format!("value{key_id}"))
+            .unwrap();
+    }
+    store.remove("key7".to_owned()).unwrap();
+    drop(store);
+
+    let migrate = || {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["migrate", "--from", "kvs", "--to", "sled", "--data-dir"])
+            .arg(&source)
+            .arg("--dest")
+            .arg(&dest)
+            .current_dir(&temp_dir);
+        cmd
+    };
+    migrate().assert().success();
+    migrate()
+        .assert()
+        .failure()
+        .stderr(contains("already holds data"));
+    migrate().arg("--force").assert().success();
+
+    let config = ServerConfig::load(dest.join("config.json")).unwrap();
+    assert_eq!(config.engine, "sled");
+    let store = SledStore::open(&dest).unwrap();
+    assert_eq!(store.approx_key_count().unwrap(), 99);
+    assert_eq!(
+        store.get("key42".to_owned()).unwrap(),
+        Some("value42".to_owned())
+    );
+    assert_eq!(store.get("key7".to_owned()).unwrap(), None);
+    drop(store);
+
+    // the source isn't sled data
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs", "--data-dir"])
+        .arg(&source)
+        .arg("--dest")
+        .arg(temp_dir.path().join("other"))
+        .assert()
+        .failure()
+        .stderr(contains("Wrong engine: expected sled, found kvs"));
+}
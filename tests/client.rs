@@ -0,0 +1,247 @@
+mod common;
+
+use common::start_server;
+use kvs::client::{KvsClient, ShardedKvsClient};
+use kvs::proto::KeyResult;
+use kvs::server::{KvServer, ServerConfig};
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KvsEngine, KvsError, Result, SledStore, ThreadPool, WriteBatch};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+#[test]
+fn client_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    assert_eq!(client.set("key1".to_owned(), "value0".to_owned())?, None);
+    assert_eq!(
+        client.set("key1".to_owned(), "value1".to_owned())?,
+        Some("value0".to_owned())
+    );
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key3".to_owned())?, None);
+    assert_eq!(client.remove("key1".to_owned())?, "value1");
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        client.scan(None, None)?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+    assert_eq!(
+        client.scan(Some("key2".to_owned()), Some(1))?,
+        vec![("key3".to_owned(), "value3".to_owned())]
+    );
+    Ok(())
+}
+
+#[test]
+fn client_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.set("key2".to_owned(), "value2".to_owned());
+    batch.remove("key1".to_owned());
+    client.write_batch(batch)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_cas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    assert!(client.cas("key1".to_owned(), None, Some("value1".to_owned()))?);
+    assert!(!client.cas("key1".to_owned(), None, Some("value2".to_owned()))?);
+    assert!(client.cas(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(client.cas("key1".to_owned(), Some("value2".to_owned()), None)?);
+    assert_eq!(client.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn client_get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    let keys = vec!["key3".to_owned(), "key2".to_owned(), "key1".to_owned()];
+    assert_eq!(
+        client.get_many(keys)?,
+        vec![
+            KeyResult::Found("value3".to_owned()),
+            KeyResult::NotFound,
+            KeyResult::Found("value1".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// A key that fails to read should report an error in its own slot only
+#[test]
+fn client_get_many_partial_failure() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    // sled stores any bytes, but `get` only returns UTF-8
+    store.set_bytes("binary".to_owned(), vec![0xff, 0xfe])?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvServer::new(ServerConfig::new("sled".to_owned()));
+    let pool = SharedQueueThreadPool::new(2)?;
+    thread::spawn(move || server.run(listener, store, pool));
+
+    let mut client = KvsClient::connect(addr)?;
+    let keys = vec!["key1".to_owned(), "binary".to_owned(), "key2".to_owned()];
+    let results = client.get_many(keys)?;
+    assert_eq!(results[0], KeyResult::Found("value1".to_owned()));
+    assert!(matches!(&results[1], KeyResult::Error(msg) if msg.contains("binary")));
+    assert_eq!(results[2], KeyResult::NotFound);
+    Ok(())
+}
+
+#[test]
+fn client_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert!(client.exists("key1".to_owned())?);
+    assert!(!client.exists("key2".to_owned())?);
+    assert!(!client.exists("key3".to_owned())?);
+    Ok(())
+}
+
+#[test]
+fn client_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.clear()?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.scan(None, None)?, Vec::new());
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// More entries than fit in one export page should all make it to the other server
+#[test]
+fn client_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvsClient::connect(start_server(&temp_dir)?)?;
+    let mut batch = WriteBatch::new();
+    for key_id in 0..2500 {
+        batch.set(format!("key{key_id}"), format!("value{key_id}"));
+    }
+    source.write_batch(batch)?;
+
+    let mut dump = Vec::new();
+    assert_eq!(source.export(&mut dump)?, 2500);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut dest = KvsClient::connect(start_server(&temp_dir)?)?;
+    assert_eq!(dest.import(dump.as_slice())?, 2500);
+    assert_eq!(dest.scan(None, None)?, source.scan(None, None)?);
+    Ok(())
+}
+
+#[test]
+fn client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = KvsClient::connect(start_server(&temp_dir)?)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = client.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert!(stats.stale_bytes > 0);
+    assert!(stats.disk_bytes > stats.stale_bytes);
+    Ok(())
+}
+
+// Keys should spread over both servers, each key living only on the one it routes to
+#[test]
+fn sharded_client_routes_keys() -> Result<()> {
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let addrs = vec![start_server(&temp_dirs[0])?, start_server(&temp_dirs[1])?];
+    let mut sharded = ShardedKvsClient::new(addrs.clone());
+    for key_id in 0..100 {
+        sharded.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut direct = [
+        KvsClient::connect(&addrs[0])?,
+        KvsClient::connect(&addrs[1])?,
+    ];
+    let mut owned = [0, 0];
+    for key_id in 0..100 {
+        let key = format!("key{:03}", key_id);
+        let owner = addrs
+            .iter()
+            .position(|a| a == sharded.server_for(&key))
+            .unwrap();
+        owned[owner] += 1;
+        assert_eq!(
+            direct[owner].get(key.clone())?,
+            Some(format!("value{}", key_id))
+        );
+        assert_eq!(direct[1 - owner].get(key.clone())?, None);
+        assert_eq!(sharded.get(key)?, Some(format!("value{}", key_id)));
+    }
+    assert!(owned[0] > 10 && owned[1] > 10, "unbalanced: {:?}", owned);
+
+    sharded.remove("key000".to_owned())?;
+    let entries = sharded.scan(None, Some(10))?;
+    let keys: Vec<_> = entries.into_iter().map(|(key, _)| key).collect();
+    let expected: Vec<_> = (1..11).map(|key_id| format!("key{:03}", key_id)).collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+// Keys owned by a server that is down should fail rather than move to another server
+#[test]
+fn sharded_client_server_down() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let up = start_server(&temp_dir)?;
+    let down = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string();
+    let mut sharded = ShardedKvsClient::new(vec![up.clone(), down.clone()]);
+
+    let mut failed = 0;
+    for key_id in 0..50 {
+        let key = format!("key{}", key_id);
+        let result = sharded.set(key.clone(), "value".to_owned());
+        if sharded.server_for(&key) == down {
+            assert!(result.is_err());
+            failed += 1;
+        } else {
+            result?;
+        }
+    }
+    assert!(failed > 0 && failed < 50);
+    assert!(sharded.scan(None, None).is_err());
+    Ok(())
+}
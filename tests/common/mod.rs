@@ -0,0 +1,18 @@
+use kvs::server::{KvServer, ServerConfig};
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{KvStore, Result, ThreadPool};
+use std::net::TcpListener;
+use std::thread;
+use tempfile::TempDir;
+
+/// Start an in-process server on an ephemeral port, returning its address.
+pub fn start_server(temp_dir: &TempDir) -> Result<String> {
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let server = KvServer::new(ServerConfig::new("kvs".to_owned()));
+    // the accept loop never returns, the thread ends with the test process
+    thread::spawn(move || server.run(listener, store, pool));
+    Ok(addr)
+}
@@ -0,0 +1,133 @@
+use assert_cmd::prelude::*;
+use predicates::str::{contains, is_empty};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use tempfile::TempDir;
+
+/// A `kvs-server` child listening on an ephemeral port, killed on drop.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start(engine: &str, pool: &str, temp_dir: &TempDir) -> Server {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args([
+                "--engine",
+                engine,
+                "--thread-pool",
+                pool,
+                "--addr",
+                "127.0.0.1:0",
+            ])
+            .current_dir(temp_dir)
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // the server logs the address it actually bound
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        let addr = loop {
+            line.clear();
+            if stderr.read_line(&mut line).unwrap() == 0 {
+                panic!("server exited before listening");
+            }
+            if let Some((_, addr)) = line.split_once("Listen at ") {
+                break addr.trim().to_string();
+            }
+        };
+        // keep draining the log so the server never blocks on a full pipe
+        thread::spawn(move || {
+            let _ = stderr.read_to_end(&mut Vec::new());
+        });
+
+        Server { child, addr }
+    }
+
+    fn client(&self, args: &[&str]) -> assert_cmd::assert::Assert {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", &self.addr])
+            .assert()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+        self.child.wait().unwrap();
+    }
+}
+
+fn full_stack(engine: &str, pool: &str) {
+    let temp_dir = TempDir::new().unwrap();
+
+    let server = Server::start(engine, pool, &temp_dir);
+    server
+        .client(&["set", "key1", "value1"])
+        .success()
+        .stdout(is_empty());
+    server.client(&["get", "key1"]).success().stdout("value1\n");
+    server
+        .client(&["set", "key1", "value2"])
+        .success()
+        .stdout(is_empty());
+    server.client(&["get", "key1"]).success().stdout("value2\n");
+    server
+        .client(&["get", "key2"])
+        .success()
+        .stdout(contains("Key not found"));
+    server
+        .client(&["rm", "key2"])
+        .failure()
+        .stderr(contains("Key not found"));
+    server
+        .client(&["set", "key2", "value3"])
+        .success()
+        .stdout(is_empty());
+    server.client(&["rm", "key1"]).success().stdout(is_empty());
+    drop(server);
+
+    // Restart on the same data and check it persisted
+    let server = Server::start(engine, pool, &temp_dir);
+    server.client(&["get", "key2"]).success().stdout("value3\n");
+    server
+        .client(&["get", "key1"])
+        .success()
+        .stdout(contains("Key not found"));
+}
+
+#[test]
+fn kvs_naive() {
+    full_stack("kvs", "naive");
+}
+
+#[test]
+fn kvs_shared_queue() {
+    full_stack("kvs", "shared_queue");
+}
+
+#[test]
+fn kvs_rayon() {
+    full_stack("kvs", "rayon");
+}
+
+#[test]
+fn sled_naive() {
+    full_stack("sled", "naive");
+}
+
+#[test]
+fn sled_shared_queue() {
+    full_stack("sled", "shared_queue");
+}
+
+#[test]
+fn sled_rayon() {
+    full_stack("sled", "rayon");
+}
@@ -0,0 +1,184 @@
+use kvs::{KvStore, KvsEngine, KvsError, Limits, MemoryStore, Result, SledStore, WriteBatch};
+use std::ops::Bound;
+use tempfile::TempDir;
+
+// Behavior every engine must share: set, get, overwrite and remove, and removing a
+// missing key failing with the same error.
+fn engine_conformance<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains("key1".to_owned())?);
+    assert_eq!(engine.set("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.contains("key1".to_owned())?);
+
+    assert_eq!(
+        engine.set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.approx_key_count()?, 1);
+
+    // empty keys and values are ordinary strings
+    engine.set(String::new(), String::new())?;
+    assert_eq!(engine.get(String::new())?, Some(String::new()));
+    assert_eq!(engine.remove(String::new())?, "");
+
+    assert_eq!(engine.remove("key1".to_owned())?, "value2");
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains("key1".to_owned())?);
+    match engine.remove("key1".to_owned()) {
+        Err(e @ KvsError::KeyNotFound) => assert_eq!(e.to_string(), "Key not found"),
+        other => panic!("unexpected result: {other:?}"),
+    }
+    assert_eq!(engine.remove_opt("key1".to_owned())?, None);
+    assert_eq!(engine.approx_key_count()?, 0);
+    Ok(())
+}
+
+// Writes, overwrites and removes should all survive reopening the engine.
+fn reopen_conformance<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let engine = open()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert!(!engine.contains("key3".to_owned())?);
+    assert!(engine.contains("key2".to_owned())?);
+    assert!(matches!(
+        engine.remove("key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.approx_key_count()?, 2);
+    Ok(())
+}
+
+// A clear should remove every key for good, and leave the engine usable.
+fn clear_conformance<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+    let engine = open()?;
+    for key_id in 0..100 {
+        engine.set(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    engine.clear()?;
+    for key_id in 0..100 {
+        assert_eq!(engine.get(format!("key{key_id}"))?, None);
+    }
+    assert_eq!(engine.approx_key_count()?, 0);
+    assert_eq!(engine.scan(Bound::Unbounded, Bound::Unbounded)?, Vec::new());
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    drop(engine);
+
+    let engine = open()?;
+    assert_eq!(engine.get("key0".to_owned())?, None);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.approx_key_count()?, 1);
+    Ok(())
+}
+
+const LIMITS: Limits = Limits {
+    max_key_size: 8,
+    max_value_size: 16,
+};
+
+// Writes over `LIMITS` must fail before changing anything, writes at the limits succeed.
+fn limits_conformance<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = "k".repeat(8);
+    let value = "v".repeat(16);
+    assert!(matches!(
+        engine.set("k".repeat(9), value.clone()),
+        Err(KvsError::KeyTooLarge { size: 9, max: 8 })
+    ));
+    assert!(matches!(
+        engine.set(key.clone(), "v".repeat(17)),
+        Err(KvsError::ValueTooLarge { size: 17, max: 16 })
+    ));
+    assert!(matches!(
+        engine.cas(key.clone(), None, Some("v".repeat(17))),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    let mut batch = WriteBatch::new();
+    batch.set(key.clone(), value.clone());
+    batch.set("key2".to_owned(), "v".repeat(17));
+    assert!(matches!(
+        engine.write_batch(batch),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert_eq!(engine.get(key.clone())?, None);
+    assert_eq!(engine.approx_key_count()?, 0);
+
+    engine.set(key.clone(), value.clone())?;
+    assert_eq!(engine.get(key)?, Some(value));
+    Ok(())
+}
+
+#[test]
+fn kvs_conformance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_conformance(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    limits_conformance(KvStore::open(temp_dir.path())?.with_limits(LIMITS))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    reopen_conformance(|| KvStore::open(temp_dir.path()))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    clear_conformance(|| KvStore::open(temp_dir.path()))
+}
+
+#[test]
+fn sled_conformance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_conformance(SledStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    limits_conformance(SledStore::open(temp_dir.path())?.with_limits(LIMITS))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    reopen_conformance(|| SledStore::open(temp_dir.path()))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    clear_conformance(|| SledStore::open(temp_dir.path()))
+}
+
+// Nothing to reopen, a new store starts empty
+#[test]
+fn memory_conformance() -> Result<()> {
+    engine_conformance(MemoryStore::new())?;
+    limits_conformance(MemoryStore::new().with_limits(LIMITS))?;
+    let store = MemoryStore::new();
+    clear_conformance(|| Ok(store.clone()))
+}
+
+// An export should hold only current values and load into a different engine
+#[test]
+fn export_sled_import_kvs() -> Result<()> {
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledStore::open(sled_dir.path())?;
+    for key_id in 0..50 {
+        sled.set(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    sled.set("key1".to_owned(), "changed".to_owned())?;
+    sled.remove("key2".to_owned())?;
+    // not ASCII and in need of escaping
+    sled.set("key \"3\"\n".to_owned(), "värde".to_owned())?;
+
+    let mut dump = Vec::new();
+    assert_eq!(sled.export(&mut dump)?, 50);
+    assert_eq!(dump.iter().filter(|b| **b == b'\n').count(), 50);
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = KvStore::open(kvs_dir.path())?;
+    assert_eq!(kvs.import(dump.as_slice())?, 50);
+    assert_eq!(
+        kvs.scan(Bound::Unbounded, Bound::Unbounded)?,
+        sled.scan(Bound::Unbounded, Bound::Unbounded)?
+    );
+    assert_eq!(kvs.get("key2".to_owned())?, None);
+
+    assert!(matches!(
+        kvs.import(&b"{\"key\":\"key1\"}\n"[..]),
+        Err(KvsError::Corrupt(_))
+    ));
+    Ok(())
+}
@@ -1,7 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::env::current_dir;
-use std::sync::{Arc, Barrier};
+use kvs::engines::kv::{ChangeEvent, KvOptions};
+use kvs::{KvStore, KvsEngine, KvsError, MemoryStore, Result, SledStore, WriteBatch};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -9,7 +15,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -19,7 +25,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -30,7 +36,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -39,7 +45,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -47,18 +53,34 @@ fn overwrite_value() -> Result<()> {
     Ok(())
 }
 
+// The last write should survive dropping the store without a flush or compaction
+#[test]
+fn drop_keeps_last_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clone.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    drop(clone);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
 // Should get `None` when getting a non-existent key
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -67,27 +89,50 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+#[test]
+fn remove_opt_reports_presence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.remove_opt("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        store.remove_opt("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.remove_opt("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Open from disk again and check the removal persisted
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.remove_opt("key1".to_owned())?, None);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -117,7 +162,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -128,6 +173,220 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// A smaller compaction threshold should compact sooner, and the other options shouldn't
+// change what is stored
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvOptions {
+        compaction_threshold: 4 * 1024,
+        flush_on_write: true,
+        buffer_size: 64,
+        ..KvOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let log_size = || fs::metadata(temp_dir.path().join("log")).unwrap().len();
+
+    let value = "v".repeat(100);
+    let mut peak = 0;
+    for iter in 0..200 {
+        store.set("key".to_owned(), format!("{value}{iter}"))?;
+        peak = peak.max(log_size());
+    }
+    // the default 1 MiB threshold would let all 200 records of over 100 bytes pile up
+    assert!(peak < 8 * 1024, "log grew to {peak} bytes");
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{value}199")));
+    Ok(())
+}
+
+// With `flush_interval`, writes are synced by the flusher rather than one by one, and
+// are all on disk once the interval has passed
+#[test]
+fn flush_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wait_synced = |store: &KvStore| {
+        for _ in 0..200 {
+            if store.synced_bytes() == store.stats().disk_bytes {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the flusher never synced the log");
+    };
+    let options = KvOptions {
+        flush_interval: Some(Duration::from_millis(200)),
+        ..KvOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // the writes reached the OS, but weren't synced one by one
+    assert!(store.synced_bytes() < store.stats().disk_bytes);
+    wait_synced(&store);
+    drop(store);
+
+    // buffered writes are written out and synced at the shorter interval too
+    let options = KvOptions {
+        flush_every: Some(Duration::from_secs(60)),
+        ..options
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("buffered".to_owned(), "value".to_owned())?;
+    assert!(store.synced_bytes() < store.stats().disk_bytes);
+    wait_synced(&store);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("buffered".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// With `flush_every`, writes should stay buffered but readable until `sync` or the
+// flusher writes them out, and a crash should only lose writes after the last `sync`
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || fs::metadata(temp_dir.path().join("log")).unwrap().len();
+    let options = KvOptions {
+        flush_every: Some(Duration::from_secs(60)),
+        buffer_size: 1024 * 1024,
+        ..KvOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let empty = log_size();
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(log_size(), empty);
+
+    store.sync()?;
+    let synced = log_size();
+    assert!(synced > empty);
+    store.set("read".to_owned(), "back".to_owned())?;
+    assert_eq!(log_size(), synced);
+    // reading a buffered record writes the buffer out first
+    assert_eq!(store.get("read".to_owned())?, Some("back".to_owned()));
+    assert_eq!(store.scan(Bound::Unbounded, Bound::Unbounded)?.len(), 11);
+    store.set("lost".to_owned(), "value".to_owned())?;
+
+    // a crash skips the flush on drop
+    std::mem::forget(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    assert_eq!(store.get("read".to_owned())?, Some("back".to_owned()));
+    assert_eq!(store.get("lost".to_owned())?, None);
+    drop(store);
+
+    // the flusher writes the buffer out without any help
+    let options = KvOptions {
+        flush_every: Some(Duration::from_millis(10)),
+        ..options
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let before = log_size();
+    store.set("timed".to_owned(), "value".to_owned())?;
+    let mut waited = 0;
+    while log_size() == before {
+        assert!(waited < 200, "the flusher never wrote the log out");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    Ok(())
+}
+
+// A clear should shrink the log back to an empty one, and reads should stop finding keys
+#[test]
+fn clear_shrinks_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || fs::metadata(temp_dir.path().join("log")).unwrap().len();
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = log_size();
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let cursor = store.change_cursor();
+    assert!(log_size() > empty);
+
+    store.clear()?;
+    assert_eq!(log_size(), empty);
+    assert_eq!(store.stats().disk_bytes, empty);
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    // followers of the change feed see every key go
+    let (events, _) = store.changes_since(cursor)?;
+    assert_eq!(events.len(), 1000);
+    Ok(())
+}
+
+// `iter` should yield every live key exactly once, as of when it was called
+#[test]
+fn iter_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{key_id}"), format!("value{key_id}"))?;
+    }
+    store.remove("key50".to_owned())?;
+
+    let mut iter = store.iter();
+    let first = iter.next().unwrap()?;
+    // writes and a compaction mid-iteration don't show up in it
+    for key_id in 0..100 {
+        store.set(format!("key{key_id}"), "new".to_owned())?;
+    }
+    store.set("key999".to_owned(), "value999".to_owned())?;
+    store.compact()?;
+
+    let mut seen = HashSet::new();
+    for entry in std::iter::once(Ok(first)).chain(iter) {
+        let (key, value) = entry?;
+        assert_eq!(value, key.replace("key", "value"));
+        assert!(seen.insert(key), "key yielded twice");
+    }
+    assert_eq!(seen.len(), 99);
+    assert!(!seen.contains("key50") && !seen.contains("key999"));
+    Ok(())
+}
+
+// Overwriting one key should keep the log bounded instead of growing with every set
+#[test]
+fn auto_compaction_same_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_size = || fs::metadata(temp_dir.path().join("log")).unwrap().len();
+
+    let value = "v".repeat(100);
+    let mut peak = 0;
+    for iter in 0..20000 {
+        store.set("key".to_owned(), format!("{value}{iter}"))?;
+        peak = peak.max(log_size());
+    }
+    // 20000 records of over 100 bytes each, far more than the log kept at its largest
+    assert!(peak < 2 * 1024 * 1024, "log grew to {peak} bytes");
+    assert!(log_size() < peak);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some(format!("{value}19999")));
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -210,3 +469,696 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Gets released all at once must each read their own record, with none failing or
+// waiting on another
+#[test]
+fn simultaneous_gets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..64 {
+        store.set(format!("key{}", i), format!("value{}", i).repeat(100))?;
+    }
+
+    let start = Arc::new(Barrier::new(64));
+    let handles: Vec<_> = (0..64)
+        .map(|thread_id| {
+            let store = store.clone();
+            let start = Arc::clone(&start);
+            thread::spawn(move || {
+                start.wait();
+                for i in 0..64 {
+                    let key_id = (i + thread_id) % 64;
+                    assert_eq!(
+                        store.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id).repeat(100))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// Should move exactly the keys in `[start, end)` to the destination store
+#[test]
+fn drain_range_to() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStore::open(src_dir.path())?;
+    let dest = KvStore::open(dest_dir.path())?;
+
+    for i in 0..10 {
+        src.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let moved = src.drain_range_to("key3".to_owned(), "key7".to_owned(), &dest)?;
+    assert_eq!(moved, 4);
+
+    for i in 0..10 {
+        let key = format!("key{}", i);
+        if (3..7).contains(&i) {
+            assert_eq!(src.get(key.clone())?, None);
+            assert_eq!(dest.get(key)?, Some(format!("value{}", i)));
+        } else {
+            assert_eq!(src.get(key.clone())?, Some(format!("value{}", i)));
+            assert_eq!(dest.get(key)?, None);
+        }
+    }
+
+    // Open from disk again and check persistent data
+    drop(src);
+    drop(dest);
+    let src = KvStore::open(src_dir.path())?;
+    let dest = KvStore::open(dest_dir.path())?;
+    assert_eq!(src.get("key5".to_owned())?, None);
+    assert_eq!(dest.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Should report exactly the mutations made after a cursor, in order
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let cursor = store.change_cursor();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let (events, next) = store.changes_since(cursor)?;
+    assert_eq!(
+        events,
+        vec![
+            ChangeEvent::Set {
+                seq: cursor + 1,
+                key: "key2".to_owned(),
+                value: "value2".to_owned()
+            },
+            ChangeEvent::Set {
+                seq: cursor + 2,
+                key: "key1".to_owned(),
+                value: "value3".to_owned()
+            },
+            ChangeEvent::Remove {
+                seq: cursor + 3,
+                key: "key2".to_owned()
+            },
+        ]
+    );
+    assert_eq!(next, cursor + 3);
+
+    let (events, after) = store.changes_since(next)?;
+    assert!(events.is_empty());
+    assert_eq!(after, next);
+
+    // A cursor that fell out of the retained window is rejected
+    for i in 0..2000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(store.changes_since(cursor).is_err());
+
+    Ok(())
+}
+
+// Gets for keys that were never set should be answered by the bloom filter
+#[test]
+fn bloom_filters_absent_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_bloom(temp_dir.path(), 1000, 0.01)?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    for i in 0..10000 {
+        assert_eq!(store.get(format!("absent{}", i))?, None);
+    }
+    // allow for the configured false-positive rate
+    assert!(store.filtered_gets() > 9500);
+
+    // present keys always reach the index
+    let filtered = store.filtered_gets();
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.filtered_gets(), filtered);
+
+    // the filter is rebuilt from the log on reopen
+    drop(store);
+    let store = KvStore::open_with_bloom(temp_dir.path(), 1000, 0.01)?;
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(store.get("absent42".to_owned())?, None);
+
+    Ok(())
+}
+
+fn assert_send_sync<T: Send + Sync + 'static>() {}
+
+// The key count is exact for this engine, across overwrites, removes and reopening
+#[test]
+fn approx_key_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100_000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "other".to_owned())?;
+        store.remove(format!("key{}", key_id + 1000))?;
+    }
+    assert_eq!(store.approx_key_count()?, 99_000);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.approx_key_count()?, 99_000);
+    Ok(())
+}
+
+// Page through `store` ten entries at a time, inserting keys ahead of the cursor
+// between pages, and check every key shows up exactly once and in order.
+fn paginate(store: &impl KvsEngine) -> Result<()> {
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key050".to_owned())?;
+
+    let mut seen = Vec::new();
+    let mut inserted = Vec::new();
+    let mut after = None;
+    loop {
+        let page = store
+            .cursor(after.clone())?
+            .take(10)
+            .collect::<Result<Vec<_>>>()?;
+        let Some((last, _)) = page.last() else {
+            break;
+        };
+        after = Some(last.clone());
+        if inserted.len() < 5 {
+            // lands after the cursor and must still be returned once
+            store.set(format!("{}x", last), "late".to_owned())?;
+            inserted.push(format!("{}x", last));
+        }
+        seen.extend(page.into_iter().map(|(key, _)| key));
+    }
+
+    let mut expected = Vec::new();
+    for key_id in (0..100).filter(|key_id| *key_id != 50) {
+        expected.push(format!("key{:03}", key_id));
+    }
+    expected.extend(inserted);
+    expected.sort();
+    assert_eq!(seen, expected);
+    assert_eq!(store.get("key007".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn cursor_pagination() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("kvs"))?;
+    paginate(&KvStore::open(temp_dir.path().join("kvs"))?)?;
+    paginate(&SledStore::open(temp_dir.path().join("sled"))?)?;
+    paginate(&MemoryStore::new())?;
+    Ok(())
+}
+
+// Scan only the keys of a range, in order, and nothing for an inverted range.
+fn scan_ranges(store: &impl KvsEngine) -> Result<()> {
+    for key_id in (0..20).rev() {
+        store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key07".to_owned())?;
+    let keys = |start, end| -> Result<Vec<String>> {
+        Ok(store
+            .scan(start, end)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    };
+
+    assert_eq!(
+        store.scan(
+            Bound::Included("key05".to_owned()),
+            Bound::Excluded("key09".to_owned())
+        )?,
+        vec![
+            ("key05".to_owned(), "value5".to_owned()),
+            ("key06".to_owned(), "value6".to_owned()),
+            ("key08".to_owned(), "value8".to_owned()),
+        ]
+    );
+    assert_eq!(
+        keys(
+            Bound::Excluded("key17".to_owned()),
+            Bound::Included("key19".to_owned())
+        )?,
+        vec!["key18", "key19"]
+    );
+    assert_eq!(
+        keys(Bound::Unbounded, Bound::Excluded("key02".to_owned()))?,
+        vec!["key00", "key01"]
+    );
+    assert_eq!(keys(Bound::Unbounded, Bound::Unbounded)?.len(), 19);
+    assert!(keys(
+        Bound::Included("key10".to_owned()),
+        Bound::Excluded("key05".to_owned())
+    )?
+    .is_empty());
+    Ok(())
+}
+
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("kvs"))?;
+    scan_ranges(&KvStore::open(temp_dir.path().join("kvs"))?)?;
+    scan_ranges(&SledStore::open(temp_dir.path().join("sled"))?)?;
+    scan_ranges(&MemoryStore::new())?;
+    Ok(())
+}
+
+// Apply 100 sets and a few removes in one batch, including a set and remove of the same
+// key and a remove of a key that never existed.
+fn apply_batch(store: &impl KvsEngine) -> Result<()> {
+    store.set("old".to_owned(), "value".to_owned())?;
+    let mut batch = WriteBatch::new();
+    for key_id in 0..100 {
+        batch.set(format!("key{:03}", key_id), format!("value{}", key_id));
+    }
+    batch.remove("key010".to_owned());
+    batch.remove("key020".to_owned());
+    batch.remove("old".to_owned());
+    batch.remove("missing".to_owned());
+    batch.set("key020".to_owned(), "again".to_owned());
+    store.write_batch(batch)?;
+
+    assert_eq!(store.get("key000".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key099".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key010".to_owned())?, None);
+    assert_eq!(store.get("key020".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("old".to_owned())?, None);
+    assert_eq!(store.get("missing".to_owned())?, None);
+    assert_eq!(store.approx_key_count()?, 99);
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("kvs"))?;
+    apply_batch(&KvStore::open(temp_dir.path().join("kvs"))?)?;
+    apply_batch(&SledStore::open(temp_dir.path().join("sled"))?)?;
+    apply_batch(&MemoryStore::new())?;
+
+    // the batch's log records replay like single writes
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    assert_eq!(store.get("key020".to_owned())?, Some("again".to_owned()));
+    assert_eq!(store.get("key010".to_owned())?, None);
+    assert_eq!(store.approx_key_count()?, 99);
+    Ok(())
+}
+
+// Threads race to bump a counter with compare-and-swap. Every value must be swapped out
+// by exactly one thread, so no increment is lost or applied twice.
+fn contend_cas<E: KvsEngine>(store: E) -> Result<()> {
+    const THREADS: usize = 4;
+    const WINS: usize = 50;
+
+    assert!(!store.cas("counter".to_owned(), Some("0".to_owned()), None)?);
+    assert!(store.cas("counter".to_owned(), None, Some("0".to_owned()))?);
+    let winners = Arc::new(Mutex::new(HashSet::new()));
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let store = store.clone();
+            let winners = Arc::clone(&winners);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                let mut wins = 0;
+                while wins < WINS {
+                    let current = store.get("counter".to_owned())?.unwrap();
+                    let next = (current.parse::<usize>().unwrap() + 1).to_string();
+                    if store.cas("counter".to_owned(), Some(current.clone()), Some(next))? {
+                        assert!(winners.lock().unwrap().insert(current));
+                        wins += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let total = THREADS * WINS;
+    assert_eq!(store.get("counter".to_owned())?, Some(total.to_string()));
+    assert_eq!(winners.lock().unwrap().len(), total);
+    assert!(store.cas("counter".to_owned(), Some(total.to_string()), None)?);
+    assert_eq!(store.get("counter".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn cas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("kvs"))?;
+    contend_cas(KvStore::open(temp_dir.path().join("kvs"))?)?;
+    contend_cas(SledStore::open(temp_dir.path().join("sled"))?)?;
+    contend_cas(MemoryStore::new())?;
+    Ok(())
+}
+
+// set and remove should hand back the value they replaced or removed
+fn previous_values(store: &impl KvsEngine) -> Result<()> {
+    assert_eq!(store.set("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.remove("key1".to_owned())?, "value2");
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.remove_opt("key1".to_owned())?, None);
+    assert_eq!(store.set("key1".to_owned(), "value3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn set_and_remove_return_previous_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::create_dir(temp_dir.path().join("kvs"))?;
+    previous_values(&KvStore::open(temp_dir.path().join("kvs"))?)?;
+    previous_values(&SledStore::open(temp_dir.path().join("sled"))?)?;
+    previous_values(&MemoryStore::new())?;
+    Ok(())
+}
+
+// Recovery should read records of the current version and reject unknown future ones
+#[test]
+fn record_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log");
+    let log = fs::read(&log_path)?;
+    assert!(log.starts_with(b"kvslog\x01\n"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let mut bumped = log.clone();
+    bumped.extend_from_slice(b"\x02{\"cmd\":\"Set\",\"key\":\"key2\",\"value\":\"value2\"}\n");
+    fs::write(&log_path, &bumped)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::IncompatibleFormat(msg)) => assert!(msg.contains("version 2")),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("opened a log with an unknown record version"),
+    }
+
+    let mut header = log.clone();
+    header[6] = 2;
+    fs::write(&log_path, &header)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleFormat(_))
+    ));
+    Ok(())
+}
+
+// A record torn by a crash at the end of the log is dropped instead of failing the open
+#[test]
+fn torn_log_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log");
+    let good_len = fs::metadata(&log_path)?.len();
+    let mut log = fs::OpenOptions::new().append(true).open(&log_path)?;
+    log.write_all(b"\x01{\"cmd\":\"Set\",\"key\":\"key3\",\"val")?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(fs::metadata(&log_path)?.len(), good_len);
+
+    // new records start on a clean line and replay
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Logs written before versioning have no header or version bytes
+#[test]
+fn legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("log"),
+        concat!(
+            "{\"cmd\":\"Set\",\"key\":\"key1\",\"value\":\"value1\"}\n",
+            "{\"cmd\":\"Set\",\"key\":\"key2\",\"value\":\"value2\"}\n",
+            "{\"cmd\":\"Remove\",\"key\":\"key2\",\"value\":\"\"}\n",
+        ),
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    // compaction rewrites the log in the current format
+    store.compact()?;
+    assert!(fs::read(temp_dir.path().join("log"))?.starts_with(b"kvslog\x01\n"));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Gets racing with compaction must never read a record through a stale offset
+#[test]
+fn get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("key{}-0", key_id))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut readers = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let done = done.clone();
+        readers.push(thread::spawn(move || {
+            let mut key_id = thread_id;
+            while !done.load(Ordering::SeqCst) {
+                let key = format!("key{}", key_id % 100);
+                let value = store.get(key.clone()).unwrap().expect("key must exist");
+                assert!(
+                    value.starts_with(&format!("{}-", key)),
+                    "read {} for {}",
+                    value,
+                    key
+                );
+                key_id += 7;
+            }
+        }));
+    }
+
+    for round in 1..=20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("key{}-{}", key_id, round))?;
+        }
+        store.compact()?;
+    }
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("key{}-20", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Compaction must copy the latest records from the old log and keep appending to the new one
+#[test]
+fn compact_keeps_latest_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// The log stores strings, so only UTF-8 byte values can be set
+#[test]
+fn byte_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes("key1".to_owned(), b"value1".to_vec())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_bytes("key1".to_owned())?,
+        Some(b"value1".to_vec())
+    );
+
+    match store.set_bytes("key2".to_owned(), vec![0xff, 0xfe]) {
+        Err(KvsError::Unsupported(_)) => {}
+        other => panic!("unexpected result: {other:?}"),
+    }
+    assert_eq!(store.get_bytes("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Keys set with a TTL should disappear once it passes, across reopen and compaction
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let short = Duration::from_millis(200);
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), short)?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), short)?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    // a plain set clears the expiry
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(store.contains("key1".to_owned())?);
+
+    thread::sleep(Duration::from_millis(300));
+    assert!(!store.contains("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.remove_opt("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.approx_key_count()?, 2);
+
+    store.set_with_ttl("key4".to_owned(), "value4".to_owned(), short)?;
+    thread::sleep(Duration::from_millis(300));
+    store.compact()?;
+    let log = fs::read_to_string(temp_dir.path().join("log"))?;
+    assert!(!log.contains("key1") && !log.contains("key4"));
+    assert!(log.contains("value3"));
+    Ok(())
+}
+
+fn check_get_many(store: impl KvsEngine) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove_opt("key2".to_owned())?;
+
+    let keys = ["key3", "key2", "key4", "key1", "key3"].map(str::to_owned);
+    assert_eq!(
+        store.get_many(keys.to_vec())?,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            None,
+            Some("value1".to_owned()),
+            Some("value3".to_owned()),
+        ]
+    );
+    assert!(store.get_many(Vec::new())?.is_empty());
+    Ok(())
+}
+
+// Multi-gets should answer in the order of their keys, with None for missing ones
+#[test]
+fn get_many() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_many(KvStore::open(kvs_dir.path())?)?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_get_many(SledStore::open(sled_dir.path())?)?;
+    check_get_many(MemoryStore::new())
+}
+
+// Stats should count distinct live keys and track the stale bytes of overwrites
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().keys, 0);
+
+    let mut live = HashSet::new();
+    for i in 0..100 {
+        let key = format!("key{}", i % 30);
+        if i % 7 == 0 {
+            store.remove_opt(key.clone())?;
+            live.remove(&key);
+        } else {
+            store.set(key.clone(), format!("value{i}"))?;
+            live.insert(key);
+        }
+    }
+    let stats = store.stats();
+    assert_eq!(stats.keys, live.len() as u64);
+    assert_eq!(
+        stats.disk_bytes,
+        fs::metadata(temp_dir.path().join("log"))?.len()
+    );
+    assert!(stats.stale_bytes > 0 && stats.stale_bytes < stats.disk_bytes);
+
+    // compaction reclaims exactly the stale bytes
+    let reclaimed = store.compact()?;
+    assert_eq!(reclaimed, stats.stale_bytes);
+    let stats = store.stats();
+    assert_eq!(stats.keys, live.len() as u64);
+    assert_eq!(stats.stale_bytes, 0);
+    Ok(())
+}
+
+// Engines are shared across the server's worker threads
+#[test]
+fn engines_are_send_sync() {
+    assert_send_sync::<KvStore>();
+    assert_send_sync::<SledStore>();
+    assert_send_sync::<MemoryStore>();
+}
@@ -0,0 +1,210 @@
+use kvs::proto::{
+    max_request_len, read_frame, try_read_frame, write_frame, IncomingRequest, Request, Response,
+    MAX_FRAME_LEN,
+};
+use kvs::{Command, KvsError, Limits, Record, Result, WriteBatch};
+use std::io::{Cursor, ErrorKind};
+
+// Frames written back to back should read back in order, header included in the length
+#[test]
+fn frame_round_trip() -> Result<()> {
+    let mut buf = Vec::new();
+    let record = Record {
+        cmd: Command::Set,
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    write_frame(&mut buf, &record)?;
+    write_frame(&mut buf, &Response::Ok(Some("value1".to_owned())))?;
+    write_frame(&mut buf, &Response::Err("Key not found".to_owned()))?;
+
+    let length = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    assert_eq!(length as usize, 4 + serde_json::to_vec(&record)?.len());
+
+    let mut reader = Cursor::new(buf);
+    let decoded: Record = read_frame(&mut reader)?;
+    assert_eq!(decoded.cmd, Command::Set);
+    assert_eq!(decoded.key, "key1");
+    assert_eq!(decoded.value, "value1");
+    assert_eq!(
+        read_frame::<Response>(&mut reader)?,
+        Response::Ok(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        read_frame::<Response>(&mut reader)?,
+        Response::Err("Key not found".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn frame_too_short() {
+    let mut reader = Cursor::new(2u32.to_be_bytes().to_vec());
+    assert!(matches!(
+        read_frame::<Response>(&mut reader),
+        Err(KvsError::Corrupt(_))
+    ));
+}
+
+#[test]
+fn frame_truncated() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Response::Ok(None))?;
+    buf.pop();
+    assert!(matches!(
+        read_frame::<Response>(&mut Cursor::new(buf)),
+        Err(KvsError::Io(_))
+    ));
+    Ok(())
+}
+
+// A header may announce up to `MAX_FRAME_LEN` bytes; a body that never arrives is an
+// error once the connection ends, not an allocation of the announced size
+#[test]
+fn frame_body_never_sent() {
+    let mut buf = MAX_FRAME_LEN.to_be_bytes().to_vec();
+    buf.extend_from_slice(b"{}");
+    assert!(matches!(
+        read_frame::<Response>(&mut Cursor::new(buf)),
+        Err(KvsError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+    ));
+}
+
+// The server accepts no larger requests than clients accept responses, and smaller
+// limits shrink its cap
+#[test]
+fn request_cap_follows_limits() {
+    assert!(max_request_len(Limits::default()) <= MAX_FRAME_LEN);
+    let huge = Limits {
+        max_key_size: usize::MAX,
+        max_value_size: usize::MAX,
+    };
+    assert_eq!(max_request_len(huge), MAX_FRAME_LEN);
+    let small = Limits {
+        max_key_size: 8,
+        max_value_size: 16,
+    };
+    assert!(max_request_len(small) < 64 * 1024);
+}
+
+// A closed connection or a zero length header ends the session without an error
+#[test]
+fn frame_end_of_session() -> Result<()> {
+    let mut closed = Cursor::new(Vec::new());
+    assert_eq!(try_read_frame::<Response>(&mut closed)?, None);
+    let mut ended = Cursor::new(0u32.to_be_bytes().to_vec());
+    assert_eq!(try_read_frame::<Response>(&mut ended)?, None);
+    assert!(read_frame::<Response>(&mut Cursor::new(Vec::new())).is_err());
+    Ok(())
+}
+
+// A write batch arriving over the wire may only hold sets and removes
+#[test]
+fn write_batch_rejects_reads() {
+    let batch: WriteBatch = serde_json::from_str(
+        r#"[{"cmd":"Set","key":"key1","value":"value1"},{"cmd":"Remove","key":"key2","value":""}]"#,
+    )
+    .unwrap();
+    assert_eq!(batch.len(), 2);
+
+    let err = serde_json::from_str::<WriteBatch>(r#"[{"cmd":"Get","key":"key1","value":""}]"#)
+        .unwrap_err();
+    assert!(err.to_string().contains("Get in a write batch"));
+}
+
+fn all_requests() -> Vec<Request> {
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    batch.remove("key2".to_owned());
+    vec![
+        Request::Get {
+            key: "key1".to_owned(),
+        },
+        Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        Request::Remove {
+            key: "key1".to_owned(),
+        },
+        Request::Scan {
+            after: Some("key1".to_owned()),
+            limit: Some(10),
+        },
+        Request::Scan {
+            after: None,
+            limit: None,
+        },
+        Request::Batch { batch },
+        Request::Cas {
+            key: "key1".to_owned(),
+            expected: None,
+            new: Some("value1".to_owned()),
+        },
+        Request::Stats,
+        Request::GetMany {
+            keys: vec!["key1".to_owned(), "key2".to_owned()],
+        },
+        Request::Exists {
+            key: "key1".to_owned(),
+        },
+        Request::Clear,
+    ]
+}
+
+// Every request should survive a frame round trip, and be accepted as an incoming request
+#[test]
+fn request_round_trip() -> Result<()> {
+    for request in all_requests() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &request)?;
+        write_frame(&mut buf, &request)?;
+        let mut reader = Cursor::new(buf);
+        assert_eq!(read_frame::<Request>(&mut reader)?, request);
+        let incoming: IncomingRequest = read_frame(&mut reader)?;
+        assert_eq!(Request::try_from(incoming)?, request);
+    }
+    Ok(())
+}
+
+// Records from older clients should decode into the matching request
+#[test]
+fn legacy_record_request() -> Result<()> {
+    let legacy = |cmd, key: &str, value: &str| -> Result<Request> {
+        let record = Record {
+            cmd,
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        let incoming: IncomingRequest = serde_json::from_slice(&serde_json::to_vec(&record)?)?;
+        Request::try_from(incoming)
+    };
+    let requests = all_requests();
+    assert_eq!(legacy(Command::Get, "key1", "")?, requests[0]);
+    assert_eq!(legacy(Command::Set, "key1", "value1")?, requests[1]);
+    assert_eq!(legacy(Command::Remove, "key1", "")?, requests[2]);
+    assert_eq!(legacy(Command::Scan, "key1", "10")?, requests[3]);
+    assert_eq!(legacy(Command::Scan, "", "")?, requests[4]);
+    let batch =
+        r#"[{"cmd":"Set","key":"key1","value":"value1"},{"cmd":"Remove","key":"key2","value":""}]"#;
+    assert_eq!(legacy(Command::Batch, "", batch)?, requests[5]);
+    assert_eq!(
+        legacy(Command::Cas, "key1", r#"[null,"value1"]"#)?,
+        requests[6]
+    );
+    assert_eq!(legacy(Command::Stats, "", "")?, requests[7]);
+    assert_eq!(
+        legacy(Command::GetMany, "", r#"["key1","key2"]"#)?,
+        requests[8]
+    );
+
+    assert!(matches!(
+        legacy(Command::Scan, "", "ten"),
+        Err(KvsError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        legacy(Command::Cas, "key1", "null"),
+        Err(KvsError::InvalidRequest(_))
+    ));
+    Ok(())
+}
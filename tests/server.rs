@@ -0,0 +1,547 @@
+mod common;
+
+use common::start_server;
+use kvs::client::KvsClient;
+use kvs::engines::kv::KvOptions;
+use kvs::engines::Cursor;
+use kvs::proto::{max_request_len, read_frame, write_frame, Request, Response, MAX_FRAME_LEN};
+use kvs::server::{KvServer, ServerConfig, CONFIG_VERSION};
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{Command, KvStore, KvsEngine, Limits, Record, Result, Stats, ThreadPool, WriteBatch};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A saved config should load back identically
+#[test]
+fn config_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("config.json");
+
+    let mut config = ServerConfig::new("sled".to_owned());
+    config.thread_pool = "rayon".to_owned();
+    config.worker_num = 4;
+    config.addr = "127.0.0.1:4010".to_owned();
+    config.data_dir = temp_dir.path().to_path_buf();
+    config.save(&path)?;
+
+    assert_eq!(ServerConfig::load(&path)?, config);
+    // pretty-printed, one setting per line
+    assert!(fs::read_to_string(&path)?.contains("\n  \"engine\": \"sled\""));
+    Ok(())
+}
+
+// A config written before versioning should load with defaults and be upgraded on disk
+#[test]
+fn config_legacy_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("config.json");
+    fs::write(&path, r#"{"engine":"kvs"}"#)?;
+
+    let config = ServerConfig::load(&path)?;
+    assert_eq!(config.version, CONFIG_VERSION);
+    assert_eq!(config.engine, "kvs");
+    assert_eq!(config.thread_pool, "shared_queue");
+    assert_eq!(config.worker_num, 8);
+    assert_eq!(config.addr, "127.0.0.1:4000");
+    assert_eq!(config.data_dir, PathBuf::from("."));
+    assert_eq!(config.max_connections, None);
+
+    let upgraded: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    assert_eq!(upgraded["version"], CONFIG_VERSION);
+    Ok(())
+}
+
+// Version 1 saved the old fixed connection limit into every config; it should follow the
+// worker count after the upgrade, while a limit chosen by hand is kept
+#[test]
+fn config_v1_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("config.json");
+
+    fs::write(
+        &path,
+        r#"{"version":1,"engine":"kvs","worker_num":4,"max_connections":1024}"#,
+    )?;
+    let config = ServerConfig::load(&path)?;
+    assert_eq!(config.max_connections, None);
+    assert_eq!(config.connection_limit(), 4);
+
+    fs::write(
+        &path,
+        r#"{"version":1,"engine":"kvs","worker_num":4,"max_connections":16}"#,
+    )?;
+    let config = ServerConfig::load(&path)?;
+    assert_eq!(config.max_connections, Some(16));
+    assert_eq!(config.connection_limit(), 16);
+    Ok(())
+}
+
+fn record(cmd: Command, key: &str, value: &str) -> Record {
+    Record {
+        cmd,
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn request(addr: &str, cmd: Command, key: &str, value: &str) -> Result<Response> {
+    let mut socket = TcpStream::connect(addr)?;
+    write_frame(&mut socket, &record(cmd, key, value))?;
+    read_frame(&mut socket)
+}
+
+// Connections accepted by the server should be answered by the pool's workers
+#[test]
+fn server_dispatches_to_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    assert_eq!(
+        request(&addr, Command::Set, "key1", "value1")?,
+        Response::Ok(None)
+    );
+    assert_eq!(
+        request(&addr, Command::Get, "key1", "")?,
+        Response::Ok(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        request(&addr, Command::Get, "key2", "")?,
+        Response::Ok(None)
+    );
+    Ok(())
+}
+
+// One connection should carry requests until a zero length header ends the session
+#[test]
+fn server_keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    let mut socket = TcpStream::connect(&addr)?;
+    for key_id in 0..10 {
+        let key = format!("key{}", key_id);
+        write_frame(&mut socket, &record(Command::Set, &key, "value"))?;
+        assert_eq!(read_frame::<Response>(&mut socket)?, Response::Ok(None));
+    }
+    write_frame(&mut socket, &record(Command::Remove, "key0", ""))?;
+    assert_eq!(
+        read_frame::<Response>(&mut socket)?,
+        Response::Ok(Some("value".to_owned()))
+    );
+    write_frame(&mut socket, &record(Command::Get, "key9", ""))?;
+    assert_eq!(
+        read_frame::<Response>(&mut socket)?,
+        Response::Ok(Some("value".to_owned()))
+    );
+
+    socket.write_all(&0u32.to_be_bytes())?;
+    assert_eq!(socket.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+// Every answered request should be counted, and failed ones as errors too
+#[test]
+fn server_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let server = KvServer::new(ServerConfig::new("kvs".to_owned()));
+    let metrics = server.metrics();
+    thread::spawn(move || server.run(listener, store, pool));
+
+    request(&addr, Command::Set, "key1", "value1")?;
+    assert_eq!(metrics.requests(), 1);
+    assert_eq!(metrics.errors(), 0);
+
+    // a missing key is an answer, not an error
+    request(&addr, Command::Remove, "key2", "")?;
+    assert!(matches!(
+        request(&addr, Command::Scan, "", "ten")?,
+        Response::Err(_)
+    ));
+    assert_eq!(metrics.requests(), 3);
+    assert_eq!(metrics.errors(), 1);
+    Ok(())
+}
+
+// Connections past `max_connections` should be told the server is busy and closed, and
+// a closed connection should make room for a new one
+#[test]
+fn server_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.max_connections = Some(2);
+    let server = KvServer::new(config);
+    thread::spawn(move || server.run(listener, store, pool));
+
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let mut socket = TcpStream::connect(&addr)?;
+        write_frame(&mut socket, &record(Command::Get, "key1", ""))?;
+        assert_eq!(read_frame::<Response>(&mut socket)?, Response::Ok(None));
+        open.push(socket);
+    }
+    for _ in 0..3 {
+        let mut socket = TcpStream::connect(&addr)?;
+        match read_frame::<Response>(&mut socket)? {
+            Response::Err(msg) => assert_eq!(msg, "Server busy: already serving 2 connections"),
+            other => panic!("unexpected response: {other:?}"),
+        }
+        assert_eq!(socket.read(&mut [0; 1])?, 0);
+    }
+    // the open connections are still served
+    write_frame(&mut open[1], &record(Command::Set, "key1", "value1"))?;
+    assert_eq!(read_frame::<Response>(&mut open[1])?, Response::Ok(None));
+
+    drop(open.remove(0));
+    let mut waited = 0;
+    while let Response::Err(_) = request(&addr, Command::Get, "key1", "")? {
+        assert!(waited < 100, "the closed connection was never released");
+        thread::sleep(Duration::from_millis(20));
+        waited += 1;
+    }
+    Ok(())
+}
+
+// Without `max_connections` a server serves one connection per worker, so a client is
+// told the server is busy rather than left waiting behind idle connections
+#[test]
+fn server_default_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.worker_num = 2;
+    let server = KvServer::new(config);
+    thread::spawn(move || server.run(listener, store, pool));
+
+    let mut open = Vec::new();
+    for _ in 0..2 {
+        let mut socket = TcpStream::connect(&addr)?;
+        write_frame(&mut socket, &record(Command::Get, "key1", ""))?;
+        assert_eq!(read_frame::<Response>(&mut socket)?, Response::Ok(None));
+        open.push(socket);
+    }
+    let mut socket = TcpStream::connect(&addr)?;
+    match read_frame::<Response>(&mut socket)? {
+        Response::Err(msg) => assert_eq!(msg, "Server busy: already serving 2 connections"),
+        other => panic!("unexpected response: {other:?}"),
+    }
+    Ok(())
+}
+
+// A frame split across many small writes should be read as one request
+#[test]
+fn server_chunked_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    let body = serde_json::to_vec(&record(Command::Set, "key1", "value1"))?;
+    let mut frame = (body.len() as u32 + 4).to_be_bytes().to_vec();
+    frame.extend_from_slice(&body);
+
+    let mut socket = TcpStream::connect(&addr)?;
+    socket.set_nodelay(true)?;
+    // the first chunks split the header itself
+    for chunk in frame.chunks(3) {
+        socket.write_all(chunk)?;
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(read_frame::<Response>(&mut socket)?, Response::Ok(None));
+    assert_eq!(
+        request(&addr, Command::Get, "key1", "")?,
+        Response::Ok(Some("value1".to_owned()))
+    );
+    Ok(())
+}
+
+// A malformed frame should close its connection without taking down the worker
+#[test]
+fn server_malformed_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    let mut socket = TcpStream::connect(&addr)?;
+    socket.write_all(&12u32.to_be_bytes())?;
+    socket.write_all(b"not json")?;
+    assert_eq!(socket.read(&mut [0; 1])?, 0);
+
+    // both workers are still around to answer
+    for _ in 0..4 {
+        assert_eq!(
+            request(&addr, Command::Get, "key1", "")?,
+            Response::Ok(None)
+        );
+    }
+    Ok(())
+}
+
+// Oversized writes should be refused by the engine, and oversized frames before their
+// body is read
+#[test]
+fn server_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limits = Limits {
+        max_key_size: 8,
+        max_value_size: 16,
+    };
+    let store = KvStore::open(temp_dir.path())?.with_limits(limits);
+    let pool = SharedQueueThreadPool::new(2)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.max_key_size = limits.max_key_size;
+    config.max_value_size = limits.max_value_size;
+    let server = KvServer::new(config);
+    thread::spawn(move || server.run(listener, store, pool));
+
+    match request(&addr, Command::Set, "key1", &"v".repeat(17))? {
+        Response::Err(msg) => assert!(msg.contains("Value of 17 bytes"), "{msg}"),
+        other => panic!("unexpected response: {other:?}"),
+    }
+    match request(&addr, Command::Set, "key123456", "value")? {
+        Response::Err(msg) => assert!(msg.contains("Key of 9 bytes"), "{msg}"),
+        other => panic!("unexpected response: {other:?}"),
+    }
+    assert_eq!(
+        request(&addr, Command::Get, "key1", "")?,
+        Response::Ok(None)
+    );
+
+    // the frame cap follows the configured limits; only the header is sent, the server
+    // must not wait for or allocate the body
+    let max_len = max_request_len(limits);
+    assert!(max_len < MAX_FRAME_LEN);
+    let mut socket = TcpStream::connect(&addr)?;
+    socket.write_all(&(max_len + 1).to_be_bytes())?;
+    match read_frame::<Response>(&mut socket)? {
+        Response::Err(msg) => assert!(
+            msg.contains(&format!("over the limit of {max_len} bytes")),
+            "{msg}"
+        ),
+        other => panic!("unexpected response: {other:?}"),
+    }
+    assert_eq!(socket.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+/// `KvStore` that counts the `get`s and `sync`s reaching it.
+#[derive(Clone)]
+struct CountingStore {
+    inner: KvStore,
+    gets: Arc<AtomicUsize>,
+    syncs: Arc<AtomicUsize>,
+}
+
+impl KvsEngine for CountingStore {
+    fn set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        self.inner.get(key)
+    }
+
+    fn approx_key_count(&self) -> Result<u64> {
+        self.inner.approx_key_count()
+    }
+
+    fn cursor(&self, after: Option<String>) -> Result<Cursor> {
+        self.inner.cursor(after)
+    }
+
+    fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        self.inner.scan(start, end)
+    }
+
+    fn cas(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        self.inner.cas(key, expected, new)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.inner.write_batch(batch)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        KvsEngine::stats(&self.inner)
+    }
+
+    fn remove_opt(&self, key: String) -> Result<Option<String>> {
+        self.inner.remove_opt(key)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync()
+    }
+}
+
+// Cached gets should skip the engine until a write to the key invalidates them
+#[test]
+fn server_read_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gets = Arc::new(AtomicUsize::new(0));
+    let store = CountingStore {
+        inner: KvStore::open(temp_dir.path())?,
+        gets: Arc::clone(&gets),
+        syncs: Arc::default(),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.cache_capacity = 16;
+    // long enough that nothing expires during the test
+    config.cache_ttl_ms = 60_000;
+    let server = KvServer::new(config);
+    let pool = SharedQueueThreadPool::new(2)?;
+    thread::spawn(move || server.run(listener, store, pool));
+
+    let mut socket = TcpStream::connect(&addr)?;
+    let mut send = |cmd, key, value| -> Result<Response> {
+        write_frame(&mut socket, &record(cmd, key, value))?;
+        read_frame(&mut socket)
+    };
+
+    send(Command::Set, "key1", "value1")?;
+    assert_eq!(
+        send(Command::Get, "key1", "")?,
+        Response::Ok(Some("value1".to_owned()))
+    );
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        send(Command::Get, "key1", "")?,
+        Response::Ok(Some("value1".to_owned()))
+    );
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+
+    send(Command::Set, "key1", "value2")?;
+    assert_eq!(
+        send(Command::Get, "key1", "")?,
+        Response::Ok(Some("value2".to_owned()))
+    );
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+    // misses are cached and invalidated the same way
+    send(Command::Remove, "key1", "")?;
+    assert_eq!(send(Command::Get, "key1", "")?, Response::Ok(None));
+    assert_eq!(send(Command::Get, "key1", "")?, Response::Ok(None));
+    assert_eq!(gets.load(Ordering::SeqCst), 3);
+
+    // a clear drops every cached key
+    send(Command::Set, "key2", "value2")?;
+    send(Command::Get, "key2", "")?;
+    // older clients can't clear, so it only comes as a `Request`
+    let mut other = TcpStream::connect(&addr)?;
+    write_frame(&mut other, &Request::Clear)?;
+    assert_eq!(read_frame::<Response>(&mut other)?, Response::Ok(None));
+    assert_eq!(send(Command::Get, "key2", "")?, Response::Ok(None));
+    assert_eq!(gets.load(Ordering::SeqCst), 5);
+    Ok(())
+}
+
+// Concurrent writes should share engine syncs under group commit, and every answered
+// write should survive a crash
+#[test]
+fn server_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let syncs = Arc::new(AtomicUsize::new(0));
+    // writes stay in the engine's buffer until a sync writes them out
+    let options = KvOptions {
+        flush_every: Some(Duration::from_secs(60)),
+        ..KvOptions::default()
+    };
+    let store = CountingStore {
+        inner: KvStore::open_with_options(temp_dir.path(), options)?,
+        gets: Arc::default(),
+        syncs: Arc::clone(&syncs),
+    };
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.commit_window_ms = 10;
+    config.worker_num = 16;
+    let server = KvServer::new(config);
+    let pool = SharedQueueThreadPool::new(16)?;
+    thread::spawn(move || server.run(listener, store, pool));
+
+    let writers: Vec<_> = (0..16)
+        .map(|writer| {
+            let addr = addr.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for i in 0..20 {
+                    client.set(format!("key{writer}-{i}"), format!("value{i}"))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    let syncs = syncs.load(Ordering::SeqCst);
+    assert!(syncs * 4 < 320, "{syncs} syncs for 320 writes");
+
+    // the server still holds its store, so this reads only what reached the file
+    let store = KvStore::open(temp_dir.path())?;
+    for writer in 0..16 {
+        for i in 0..20 {
+            assert_eq!(
+                store.get(format!("key{writer}-{i}"))?,
+                Some(format!("value{i}"))
+            );
+        }
+    }
+    Ok(())
+}
+
+// An idle keep-alive client should be disconnected after the idle timeout, so it
+// doesn't keep a second client from the only worker
+#[test]
+fn server_idle_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(1)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let mut config = ServerConfig::new("kvs".to_owned());
+    config.idle_timeout_ms = 200;
+    let server = KvServer::new(config);
+    thread::spawn(move || server.run(listener, store, pool));
+
+    // served, then left open without another request
+    let mut idle = TcpStream::connect(&addr)?;
+    write_frame(&mut idle, &record(Command::Get, "key1", ""))?;
+    assert_eq!(read_frame::<Response>(&mut idle)?, Response::Ok(None));
+
+    let mut socket = TcpStream::connect(&addr)?;
+    // fail instead of hanging if the idle client kept the worker
+    socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+    write_frame(&mut socket, &record(Command::Set, "key1", "value1"))?;
+    assert_eq!(read_frame::<Response>(&mut socket)?, Response::Ok(None));
+
+    // the idle connection was closed by the server
+    assert_eq!(idle.read(&mut [0; 1])?, 0);
+    Ok(())
+}
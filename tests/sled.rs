@@ -0,0 +1,158 @@
+use kvs::engines::sled::SledStore;
+use kvs::{KvsEngine, KvsError, Result};
+use sled::transaction::ConflictableTransactionError;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Concurrent transfers between two keys should never lose or create value
+#[test]
+fn transaction_transfer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1000".to_owned())?;
+    store.set("b".to_owned(), "0".to_owned())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..25 {
+                store
+                    .transaction(|tx| {
+                        let a: u64 = tx.get("a")?.unwrap().parse().unwrap();
+                        let b: u64 = tx.get("b")?.unwrap().parse().unwrap();
+                        tx.insert("a", (a - 1).to_string())?;
+                        tx.insert("b", (b + 1).to_string())?;
+                        Ok(())
+                    })
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("a".to_owned())?, Some("800".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// An aborted transaction should write nothing
+#[test]
+fn transaction_abort() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+
+    let result = store.transaction(|tx| {
+        tx.insert("a", "2".to_owned())?;
+        tx.remove("a")?;
+        Err::<(), _>(ConflictableTransactionError::Abort(KvsError::KeyNotFound))
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn remove_opt_reports_presence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.remove_opt("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        store.remove_opt("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn approx_key_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.approx_key_count()?, 0);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.set("key0".to_owned(), "other".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.approx_key_count()?, 99);
+    Ok(())
+}
+
+// A database stamped with another sled version should fail with a labeled error
+#[test]
+fn open_incompatible_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledStore::open(temp_dir.path())?);
+
+    let conf_path = temp_dir.path().join("conf");
+    let mut conf = fs::read(&conf_path)?;
+    // same length, so the trailing checksum stays in place
+    let at = conf
+        .windows(13)
+        .position(|w| w == b"version: 0.34")
+        .expect("sled config has a version line");
+    conf[at..at + 13].copy_from_slice(b"version: 0.01");
+    fs::write(&conf_path, conf)?;
+
+    match SledStore::open(temp_dir.path()) {
+        Err(KvsError::IncompatibleFormat(msg)) => assert!(msg.contains("0.1")),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("opened an incompatible database"),
+    }
+    Ok(())
+}
+
+// Non-UTF-8 values should round-trip as bytes, and fail cleanly when read as strings
+#[test]
+fn byte_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    let value = vec![0xff, 0x00, 0xfe, b'a', 0x80];
+    store.set_bytes("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(store.get_bytes("key3".to_owned())?, None);
+    match store.get("key1".to_owned()) {
+        Err(KvsError::Corrupt(_)) => {}
+        other => panic!("unexpected result: {other:?}"),
+    }
+
+    drop(store);
+    let store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Writes made with batched flushing should survive dropping and reopening the store
+#[test]
+fn batched_flush_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open_with_options(temp_dir.path(), Some(Duration::from_secs(60)))?;
+    for i in 0..100 {
+        store.set(format!("key{i}"), format!("value{i}"))?;
+    }
+    store.remove("key0".to_owned())?;
+    let clone = store.clone();
+    drop(store);
+    clone.set("key1".to_owned(), "changed".to_owned())?;
+    drop(clone);
+
+    let store = SledStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
@@ -1,8 +1,11 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,12 +49,58 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+// Results of jobs should come back through their own receivers, whatever order the
+// jobs finish in
+fn spawn_results<P: ThreadPool>(pool: P) -> Result<()> {
+    let receivers: Vec<_> = (0..20u64)
+        .map(|i| {
+            pool.spawn_handle(move || {
+                // later jobs finish first
+                thread::sleep(Duration::from_millis(20 - i));
+                i * i
+            })
+        })
+        .collect();
+    for (i, receiver) in receivers.into_iter().enumerate() {
+        assert_eq!(receiver.recv().unwrap(), (i * i) as u64);
+    }
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
     spawn_counter(pool)
 }
 
+// More jobs than workers: spawn waits for a free slot, and dropping joins the rest
+#[test]
+fn naive_thread_pool_caps_and_joins() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let pool = NaiveThreadPool::new(4)?;
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let running = Arc::clone(&running);
+        let peak = Arc::clone(&peak);
+        pool.spawn(move || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    assert!(peak.load(Ordering::SeqCst) <= 4);
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     let pool = SharedQueueThreadPool::new(4)?;
@@ -64,7 +113,92 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+// Zero threads is an error rather than a pool whose jobs never run, or rayon's default
+// of one per CPU
+fn zero_threads<P: ThreadPool>() {
+    match P::new(0) {
+        Err(KvsError::ThreadPool(_)) => {}
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("created a pool without threads"),
+    }
+}
+
+#[test]
+fn naive_thread_pool_zero_threads() {
+    zero_threads::<NaiveThreadPool>();
+}
+
+#[test]
+fn shared_queue_thread_pool_zero_threads() {
+    zero_threads::<SharedQueueThreadPool>();
+}
+
+#[test]
+fn rayon_thread_pool_zero_threads() {
+    zero_threads::<RayonThreadPool>();
+}
+
+#[test]
+fn naive_thread_pool_spawn_results() -> Result<()> {
+    spawn_results(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_results() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    // a panicking job hangs up instead of sending
+    let panicked = pool.spawn_handle(|| -> u64 {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    assert!(panicked.recv().is_err());
+    spawn_results(pool)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_results() -> Result<()> {
+    spawn_results(RayonThreadPool::new(4)?)
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_drop_joins() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    let pool = SharedQueueThreadPool::new(4)?;
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    // dropping waits for every queued job
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_survives_panics() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    // more panics than workers, so the pool would be empty if dead workers weren't replaced
+    for _ in 0..8 {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        })
+    }
+    let (sender, receiver) = mpsc::channel();
+    pool.spawn(move || sender.send(()).unwrap());
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("job after panics never ran");
+    Ok(())
+}